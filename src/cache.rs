@@ -0,0 +1,383 @@
+use crate::config::{AppConfig, MaskConfig};
+use crate::masking;
+use crate::types::{MaskedGeometry, PlacementZone, SmallArea};
+use anyhow::{Context, Result, anyhow};
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+// Bump when the masking algorithm or the file layout changes, so stale caches are ignored
+const MASK_CACHE_VERSION: u32 = 4;
+const MAGIC: &[u8; 4] = b"DDMC";
+// Most entries pre-allocated for a count read from a cache file, so a corrupt count fails on
+// the short read instead of on a huge allocation
+const MAX_PREALLOCATION: usize = 1 << 16;
+
+/// Masks every area (see `masking::apply_masks`), reading and writing the on-disk cache
+/// of masked geometries when `output.mask_cache` is on.
+pub fn mask_areas(config: &AppConfig, areas: &mut [SmallArea]) -> Result<()> {
+    if config.masks().is_empty() {
+        return Ok(());
+    }
+    println!("Masking enabled.");
+
+    if !config.output.mask_cache {
+        let layers = masking::load_masks(config)?;
+        masking::apply_masks(config, areas, &layers);
+        masking::print_masking_summary(areas);
+        return Ok(());
+    }
+
+    // Dataset -> mask key, for datasets with at least one mask
+    let keys: HashMap<&String, String> = config.processing.datasets.keys()
+        .map(|name| (name, config.mask_key(name)))
+        .filter(|(_, key)| !key.is_empty())
+        .collect();
+
+    // Every (level, mask key) pair some area needs
+    let mut groups: Vec<(String, String)> = areas.iter()
+        .flat_map(|area| area.population_data.keys()
+            .filter_map(|dataset| keys.get(dataset))
+            .map(|key| (area.level.clone(), key.clone())))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    groups.sort();
+
+    let mut paths = HashMap::new();
+    let mut digests = HashMap::new();
+    for (level, key) in &groups {
+        let path = cache_path(config, level, key, &mut digests)?;
+        if path.exists() {
+            match read_cache(&path) {
+                Ok(cached) => {
+                    println!("Loaded {} masked geometries from cache {:?}", cached.len(), path);
+                    for area in areas.iter_mut().filter(|a| &a.level == level) {
+                        if let Some(masked) = cached.get(&area.id) {
+                            area.masked.insert(key.clone(), masked.clone());
+                        }
+                    }
+                }
+                Err(e) => println!("  WARNING: ignoring unreadable mask cache {:?}: {:#}", path, e),
+            }
+        }
+        paths.insert((level.clone(), key.clone()), path);
+    }
+
+    // Anything the cache didn't cover gets masked now and the affected caches rewritten
+    let mut stale: HashSet<(String, String)> = HashSet::new();
+    for area in areas.iter() {
+        for key in area.population_data.keys().filter_map(|dataset| keys.get(dataset)) {
+            if !area.masked.contains_key(key) {
+                stale.insert((area.level.clone(), key.clone()));
+            }
+        }
+    }
+    if stale.is_empty() {
+        masking::print_masking_summary(areas);
+        return Ok(());
+    }
+
+    let layers = masking::load_masks(config)?;
+    masking::apply_masks(config, areas, &layers);
+
+    for group in &stale {
+        let (level, key) = group;
+        let path = &paths[group];
+        let entries: Vec<(&String, &MaskedGeometry)> = areas.iter()
+            .filter(|a| &a.level == level)
+            .filter_map(|a| a.masked.get(key).map(|m| (&a.id, m)))
+            .collect();
+        write_cache(path, &entries)
+            .with_context(|| format!("Failed to write mask cache {:?}", path))?;
+        println!("Cached {} masked geometries in {:?}", entries.len(), path);
+    }
+    masking::print_masking_summary(areas);
+    Ok(())
+}
+
+/// Cache file for one level and mask set, named after a hash of everything the masked
+/// geometry depends on: the boundary files, the mask files and their settings. File digests
+/// are kept in `digests` so each file is read once however many groups use it.
+fn cache_path(config: &AppConfig, level_name: &str, mask_key: &str, digests: &mut HashMap<PathBuf, Vec<u8>>) -> Result<PathBuf> {
+    let level = config.geography_levels().into_iter()
+        .find(|l| l.name == level_name)
+        .ok_or_else(|| anyhow!("Unknown geography level '{}'", level_name))?;
+
+    let mut hasher = Sha256::new();
+    hasher.update(MASK_CACHE_VERSION.to_le_bytes());
+    hash_str(&mut hasher, &level.join_column_shape);
+    hash_str(&mut hasher, level.crs.as_deref().unwrap_or(""));
+    hash_file_set(&mut hasher, &level.shapefile, digests)?;
+    hasher.update(config.processing.min_retained_fraction.to_le_bytes());
+
+    let masks: HashMap<String, MaskConfig> = config.masks().into_iter().map(|m| (m.name.clone(), m)).collect();
+    for name in mask_key.split('+') {
+        let mask = masks.get(name).ok_or_else(|| anyhow!("Unknown mask '{}'", name))?;
+        hash_str(&mut hasher, &mask.name);
+        hash_str(&mut hasher, &format!("{:?}", mask.mode));
+        hash_str(&mut hasher, mask.crs.as_deref().unwrap_or(""));
+        hash_str(&mut hasher, mask.weight_property.as_deref().unwrap_or(""));
+        hasher.update(mask.default_weight.to_le_bytes());
+        hasher.update(mask.background_weight.to_le_bytes());
+        hash_file_set(&mut hasher, &mask.path, digests)?;
+    }
+
+    let digest = hasher.finalize();
+    let hex: String = digest.iter().take(12).map(|b| format!("{:02x}", b)).collect();
+    let level_part: String = level_name.chars().map(|c| if c.is_alphanumeric() { c } else { '_' }).collect();
+    Ok(config.output.cache_dir.join(format!("masked-{}-{}.bin", level_part, hex)))
+}
+
+fn hash_str(hasher: &mut Sha256, value: &str) {
+    hasher.update((value.len() as u64).to_le_bytes());
+    hasher.update(value.as_bytes());
+}
+
+/// Hashes a geometry file plus the shapefile sidecars (.shx, .dbf, .prj) that exist next to it,
+/// reusing the digest from `digests` when the set was hashed before.
+fn hash_file_set(hasher: &mut Sha256, path: &Path, digests: &mut HashMap<PathBuf, Vec<u8>>) -> Result<()> {
+    if let Some(digest) = digests.get(path) {
+        hasher.update(digest);
+        return Ok(());
+    }
+    let mut file_hasher = Sha256::new();
+    hash_file(&mut file_hasher, path)?;
+    for extension in ["shx", "dbf", "prj"] {
+        let sidecar = path.with_extension(extension);
+        if sidecar != path && sidecar.exists() {
+            hash_file(&mut file_hasher, &sidecar)?;
+        }
+    }
+    let digest = file_hasher.finalize().to_vec();
+    hasher.update(&digest);
+    digests.insert(path.to_path_buf(), digest);
+    Ok(())
+}
+
+fn hash_file(hasher: &mut Sha256, path: &Path) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?} for hashing", path))?;
+    let mut reader = BufReader::new(file);
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(())
+}
+
+// --- File layout: MAGIC, version, area count, then per area its id, issue, fallback and MaskedGeometry.
+// Numbers are little endian; strings and lists are prefixed with a u32 length.
+
+fn write_cache(path: &Path, entries: &[(&String, &MaskedGeometry)]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Write to a temporary file first so an interrupted run never leaves a truncated cache
+    let tmp = path.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp)?);
+    w.write_all(MAGIC)?;
+    write_u32(&mut w, MASK_CACHE_VERSION)?;
+    write_u32(&mut w, entries.len() as u32)?;
+    for (id, masked) in entries {
+        write_str(&mut w, id)?;
+        // Issue note: empty when masking went cleanly
+        write_str(&mut w, masked.issue.as_deref().unwrap_or(""))?;
+        // Retained fraction of an unmasked fallback, negative when masking was kept
+        w.write_all(&masked.unmasked_fallback.unwrap_or(-1.0).to_le_bytes())?;
+        write_multi_polygon(&mut w, &masked.geometry)?;
+        write_u32(&mut w, masked.placement.len() as u32)?;
+        for zone in &masked.placement {
+            w.write_all(&zone.weight.to_le_bytes())?;
+            write_multi_polygon(&mut w, &zone.geometry)?;
+        }
+    }
+    w.flush()?;
+    drop(w);
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn read_cache(path: &Path) -> Result<HashMap<String, MaskedGeometry>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(anyhow!("not a mask cache file"));
+    }
+    let version = read_u32(&mut r)?;
+    if version != MASK_CACHE_VERSION {
+        return Err(anyhow!("cache version {} (expected {})", version, MASK_CACHE_VERSION));
+    }
+
+    let count = read_u32(&mut r)?;
+    let mut areas = HashMap::with_capacity(preallocation(count));
+    for _ in 0..count {
+        let id = read_str(&mut r)?;
+        let issue = Some(read_str(&mut r)?).filter(|s| !s.is_empty());
+        let unmasked_fallback = Some(read_f64(&mut r)?).filter(|f| *f >= 0.0);
+        let geometry = read_multi_polygon(&mut r)?;
+        let zones = read_u32(&mut r)?;
+        let mut placement = Vec::with_capacity(preallocation(zones));
+        for _ in 0..zones {
+            let weight = read_f64(&mut r)?;
+            placement.push(PlacementZone { geometry: read_multi_polygon(&mut r)?, weight });
+        }
+        areas.insert(id, MaskedGeometry { geometry, placement, issue, unmasked_fallback });
+    }
+    Ok(areas)
+}
+
+fn write_u32(w: &mut impl Write, value: u32) -> Result<()> {
+    w.write_all(&value.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_u32(r: &mut impl Read) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn write_str(w: &mut impl Write, value: &str) -> Result<()> {
+    write_u32(w, value.len() as u32)?;
+    w.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn read_str(r: &mut impl Read) -> Result<String> {
+    let len = read_u32(r)?;
+    let mut bytes = Vec::with_capacity(preallocation(len));
+    r.take(len.into()).read_to_end(&mut bytes)?;
+    if bytes.len() != len as usize {
+        return Err(anyhow!("truncated string"));
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+fn preallocation(count: u32) -> usize {
+    (count as usize).min(MAX_PREALLOCATION)
+}
+
+fn read_f64(r: &mut impl Read) -> Result<f64> {
+    let mut buf = [0u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+fn write_ring(w: &mut impl Write, ring: &LineString<f64>) -> Result<()> {
+    write_u32(w, ring.0.len() as u32)?;
+    for c in &ring.0 {
+        w.write_all(&c.x.to_le_bytes())?;
+        w.write_all(&c.y.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+fn read_ring(r: &mut impl Read) -> Result<LineString<f64>> {
+    let n = read_u32(r)?;
+    let mut coords = Vec::with_capacity(preallocation(n));
+    for _ in 0..n {
+        coords.push(Coord { x: read_f64(r)?, y: read_f64(r)? });
+    }
+    Ok(LineString(coords))
+}
+
+fn write_multi_polygon(w: &mut impl Write, mp: &MultiPolygon<f64>) -> Result<()> {
+    write_u32(w, mp.0.len() as u32)?;
+    for polygon in &mp.0 {
+        write_ring(w, polygon.exterior())?;
+        write_u32(w, polygon.interiors().len() as u32)?;
+        for interior in polygon.interiors() {
+            write_ring(w, interior)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_multi_polygon(r: &mut impl Read) -> Result<MultiPolygon<f64>> {
+    let n = read_u32(r)?;
+    let mut polygons = Vec::with_capacity(preallocation(n));
+    for _ in 0..n {
+        let exterior = read_ring(r)?;
+        let holes = read_u32(r)?;
+        let interiors = (0..holes).map(|_| read_ring(r)).collect::<Result<Vec<_>>>()?;
+        polygons.push(Polygon::new(exterior, interiors));
+    }
+    Ok(MultiPolygon(polygons))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f64, size: f64) -> LineString<f64> {
+        LineString::from(vec![(x, 0.0), (x + size, 0.0), (x + size, size), (x, size), (x, 0.0)])
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ddm-cache-test-{}-{}", std::process::id(), name)).join("masked.bin")
+    }
+
+    #[test]
+    fn cache_round_trips() {
+        let holed = MultiPolygon::new(vec![Polygon::new(square(0.0, 4.0), vec![square(1.0, 1.0)]), Polygon::new(square(10.0, 1.0), vec![])]);
+        let clean = MaskedGeometry { geometry: holed.clone(), placement: vec![], issue: None, unmasked_fallback: None };
+        let placed = MaskedGeometry {
+            geometry: MultiPolygon::new(vec![Polygon::new(square(-6.25, 0.001), vec![])]),
+            placement: vec![
+                PlacementZone { geometry: holed, weight: 2.5 },
+                PlacementZone { geometry: MultiPolygon::new(vec![]), weight: 0.0 },
+            ],
+            issue: Some("mask 'water': boolean op failed, réessayé".to_string()),
+            unmasked_fallback: Some(0.0),
+        };
+        let (a, b) = ("A001".to_string(), "B 002".to_string());
+        let path = temp_path("round-trip");
+        write_cache(&path, &[(&a, &clean), (&b, &placed)]).unwrap();
+        let read = read_cache(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+        assert_eq!(read.len(), 2);
+        for (id, expected) in [(&a, &clean), (&b, &placed)] {
+            let actual = &read[id];
+            assert_eq!(actual.geometry, expected.geometry);
+            assert_eq!(actual.issue, expected.issue);
+            assert_eq!(actual.unmasked_fallback, expected.unmasked_fallback);
+            assert_eq!(actual.placement.len(), expected.placement.len());
+            for (actual, expected) in actual.placement.iter().zip(&expected.placement) {
+                assert_eq!((&actual.geometry, actual.weight), (&expected.geometry, expected.weight));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_files() {
+        let masked = MaskedGeometry {
+            geometry: MultiPolygon::new(vec![Polygon::new(square(0.0, 1.0), vec![])]),
+            placement: vec![],
+            issue: None,
+            unmasked_fallback: None,
+        };
+        let id = "A001".to_string();
+        let path = temp_path("truncated");
+        write_cache(&path, &[(&id, &masked)]).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(&path, &bytes[..bytes.len() - 3]).unwrap();
+        assert!(read_cache(&path).is_err());
+        fs::write(&path, b"PK\x03\x04 not a cache").unwrap();
+        assert!(read_cache(&path).is_err());
+        let mut old = bytes.clone();
+        old[4..8].copy_from_slice(&(MASK_CACHE_VERSION - 1).to_le_bytes());
+        fs::write(&path, &old).unwrap();
+        assert!(read_cache(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
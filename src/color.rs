@@ -0,0 +1,252 @@
+use anyhow::{Result, anyhow};
+use image::Rgba;
+
+/// Parses a CSS-style colour: `#rgb`, `#rgba`, `#rrggbb`, `#rrggbbaa`, a CSS named
+/// colour, or `rgb()/rgba()/hsl()/hsla()` in either comma or space separated syntax.
+pub fn parse_color(input: &str) -> Result<Rgba<u8>> {
+    let s = input.trim().to_lowercase();
+
+    if let Some(hex) = s.strip_prefix('#') {
+        return parse_hex(hex).ok_or_else(|| anyhow!("invalid hex colour '{}'", input));
+    }
+
+    if let Some((func, args)) = split_function(&s) {
+        let args = split_args(args);
+        return match func {
+            "rgb" | "rgba" => parse_rgb(&args),
+            "hsl" | "hsla" => parse_hsl(&args),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow!("invalid colour function '{}'", input));
+    }
+
+    named_color(&s).ok_or_else(|| anyhow!("unknown colour '{}'", input))
+}
+
+/// Formats a colour as `#rrggbb`, or `#rrggbbaa` when it isn't opaque.
+pub fn to_hex(color: Rgba<u8>) -> String {
+    let [r, g, b, a] = color.0;
+    if a == 255 {
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", r, g, b, a)
+    }
+}
+
+/// Moves a colour towards black by `amount` (0 = unchanged, 1 = black), keeping alpha.
+pub fn darken(color: Rgba<u8>, amount: f64) -> Rgba<u8> {
+    let [r, g, b, a] = color.0;
+    let scale = |c: u8| (c as f64 * (1.0 - amount.clamp(0.0, 1.0))).round() as u8;
+    Rgba([scale(r), scale(g), scale(b), a])
+}
+
+fn parse_hex(hex: &str) -> Option<Rgba<u8>> {
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|v| v * 17);
+    let pair = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+
+    match hex.len() {
+        3 => Some(Rgba([digit(0)?, digit(1)?, digit(2)?, 255])),
+        4 => Some(Rgba([digit(0)?, digit(1)?, digit(2)?, digit(3)?])),
+        6 => Some(Rgba([pair(0)?, pair(2)?, pair(4)?, 255])),
+        8 => Some(Rgba([pair(0)?, pair(2)?, pair(4)?, pair(6)?])),
+        _ => None,
+    }
+}
+
+/// Splits `name(args)` into its name and argument text.
+fn split_function(s: &str) -> Option<(&str, &str)> {
+    let open = s.find('(')?;
+    let args = s[open + 1..].strip_suffix(')')?;
+    Some((s[..open].trim(), args))
+}
+
+/// Splits arguments on commas, whitespace and the `/` before alpha.
+fn split_args(args: &str) -> Vec<&str> {
+    args.split(|c: char| c == ',' || c == '/' || c.is_whitespace())
+        .filter(|a| !a.is_empty())
+        .collect()
+}
+
+/// A colour channel: a 0-255 number or a percentage.
+fn channel(arg: &str) -> Option<u8> {
+    let value = match arg.strip_suffix('%') {
+        Some(pct) => pct.parse::<f64>().ok()? / 100.0 * 255.0,
+        None => arg.parse::<f64>().ok()?,
+    };
+    Some(value.round().clamp(0.0, 255.0) as u8)
+}
+
+/// An alpha value: a 0-1 number or a percentage.
+fn alpha(arg: Option<&&str>) -> Option<u8> {
+    let Some(arg) = arg else { return Some(255) };
+    let value = match arg.strip_suffix('%') {
+        Some(pct) => pct.parse::<f64>().ok()? / 100.0,
+        None => arg.parse::<f64>().ok()?,
+    };
+    Some((value.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+fn parse_rgb(args: &[&str]) -> Option<Rgba<u8>> {
+    if args.len() != 3 && args.len() != 4 {
+        return None;
+    }
+    Some(Rgba([channel(args[0])?, channel(args[1])?, channel(args[2])?, alpha(args.get(3))?]))
+}
+
+fn parse_hsl(args: &[&str]) -> Option<Rgba<u8>> {
+    if args.len() != 3 && args.len() != 4 {
+        return None;
+    }
+    let hue = args[0].trim_end_matches("deg").parse::<f64>().ok()?.rem_euclid(360.0) / 360.0;
+    let percent = |a: &str| a.strip_suffix('%').unwrap_or(a).parse::<f64>().ok().map(|v| (v / 100.0).clamp(0.0, 1.0));
+    let sat = percent(args[1])?;
+    let light = percent(args[2])?;
+
+    let q = if light < 0.5 { light * (1.0 + sat) } else { light + sat - light * sat };
+    let p = 2.0 * light - q;
+    let to_channel = |t: f64| {
+        let t = t.rem_euclid(1.0);
+        let v = if t < 1.0 / 6.0 {
+            p + (q - p) * 6.0 * t
+        } else if t < 0.5 {
+            q
+        } else if t < 2.0 / 3.0 {
+            p + (q - p) * (2.0 / 3.0 - t) * 6.0
+        } else {
+            p
+        };
+        (v * 255.0).round() as u8
+    };
+
+    Some(Rgba([
+        to_channel(hue + 1.0 / 3.0),
+        to_channel(hue),
+        to_channel(hue - 1.0 / 3.0),
+        alpha(args.get(3))?,
+    ]))
+}
+
+fn named_color(name: &str) -> Option<Rgba<u8>> {
+    if name == "transparent" {
+        return Some(Rgba([0, 0, 0, 0]));
+    }
+    let hex = CSS_NAMED_COLORS.iter().find(|(n, _)| *n == name)?.1;
+    parse_hex(hex)
+}
+
+// CSS Color Module Level 4 named colours
+const CSS_NAMED_COLORS: &[(&str, &str)] = &[
+    ("aliceblue", "f0f8ff"), ("antiquewhite", "faebd7"), ("aqua", "00ffff"),
+    ("aquamarine", "7fffd4"), ("azure", "f0ffff"), ("beige", "f5f5dc"),
+    ("bisque", "ffe4c4"), ("black", "000000"), ("blanchedalmond", "ffebcd"),
+    ("blue", "0000ff"), ("blueviolet", "8a2be2"), ("brown", "a52a2a"),
+    ("burlywood", "deb887"), ("cadetblue", "5f9ea0"), ("chartreuse", "7fff00"),
+    ("chocolate", "d2691e"), ("coral", "ff7f50"), ("cornflowerblue", "6495ed"),
+    ("cornsilk", "fff8dc"), ("crimson", "dc143c"), ("cyan", "00ffff"),
+    ("darkblue", "00008b"), ("darkcyan", "008b8b"), ("darkgoldenrod", "b8860b"),
+    ("darkgray", "a9a9a9"), ("darkgreen", "006400"), ("darkgrey", "a9a9a9"),
+    ("darkkhaki", "bdb76b"), ("darkmagenta", "8b008b"), ("darkolivegreen", "556b2f"),
+    ("darkorange", "ff8c00"), ("darkorchid", "9932cc"), ("darkred", "8b0000"),
+    ("darksalmon", "e9967a"), ("darkseagreen", "8fbc8f"), ("darkslateblue", "483d8b"),
+    ("darkslategray", "2f4f4f"), ("darkslategrey", "2f4f4f"), ("darkturquoise", "00ced1"),
+    ("darkviolet", "9400d3"), ("deeppink", "ff1493"), ("deepskyblue", "00bfff"),
+    ("dimgray", "696969"), ("dimgrey", "696969"), ("dodgerblue", "1e90ff"),
+    ("firebrick", "b22222"), ("floralwhite", "fffaf0"), ("forestgreen", "228b22"),
+    ("fuchsia", "ff00ff"), ("gainsboro", "dcdcdc"), ("ghostwhite", "f8f8ff"),
+    ("gold", "ffd700"), ("goldenrod", "daa520"), ("gray", "808080"),
+    ("green", "008000"), ("greenyellow", "adff2f"), ("grey", "808080"),
+    ("honeydew", "f0fff0"), ("hotpink", "ff69b4"), ("indianred", "cd5c5c"),
+    ("indigo", "4b0082"), ("ivory", "fffff0"), ("khaki", "f0e68c"),
+    ("lavender", "e6e6fa"), ("lavenderblush", "fff0f5"), ("lawngreen", "7cfc00"),
+    ("lemonchiffon", "fffacd"), ("lightblue", "add8e6"), ("lightcoral", "f08080"),
+    ("lightcyan", "e0ffff"), ("lightgoldenrodyellow", "fafad2"), ("lightgray", "d3d3d3"),
+    ("lightgreen", "90ee90"), ("lightgrey", "d3d3d3"), ("lightpink", "ffb6c1"),
+    ("lightsalmon", "ffa07a"), ("lightseagreen", "20b2aa"), ("lightskyblue", "87cefa"),
+    ("lightslategray", "778899"), ("lightslategrey", "778899"), ("lightsteelblue", "b0c4de"),
+    ("lightyellow", "ffffe0"), ("lime", "00ff00"), ("limegreen", "32cd32"),
+    ("linen", "faf0e6"), ("magenta", "ff00ff"), ("maroon", "800000"),
+    ("mediumaquamarine", "66cdaa"), ("mediumblue", "0000cd"), ("mediumorchid", "ba55d3"),
+    ("mediumpurple", "9370db"), ("mediumseagreen", "3cb371"), ("mediumslateblue", "7b68ee"),
+    ("mediumspringgreen", "00fa9a"), ("mediumturquoise", "48d1cc"), ("mediumvioletred", "c71585"),
+    ("midnightblue", "191970"), ("mintcream", "f5fffa"), ("mistyrose", "ffe4e1"),
+    ("moccasin", "ffe4b5"), ("navajowhite", "ffdead"), ("navy", "000080"),
+    ("oldlace", "fdf5e6"), ("olive", "808000"), ("olivedrab", "6b8e23"),
+    ("orange", "ffa500"), ("orangered", "ff4500"), ("orchid", "da70d6"),
+    ("palegoldenrod", "eee8aa"), ("palegreen", "98fb98"), ("paleturquoise", "afeeee"),
+    ("palevioletred", "db7093"), ("papayawhip", "ffefd5"), ("peachpuff", "ffdab9"),
+    ("peru", "cd853f"), ("pink", "ffc0cb"), ("plum", "dda0dd"),
+    ("powderblue", "b0e0e6"), ("purple", "800080"), ("rebeccapurple", "663399"),
+    ("red", "ff0000"), ("rosybrown", "bc8f8f"), ("royalblue", "4169e1"),
+    ("saddlebrown", "8b4513"), ("salmon", "fa8072"), ("sandybrown", "f4a460"),
+    ("seagreen", "2e8b57"), ("seashell", "fff5ee"), ("sienna", "a0522d"),
+    ("silver", "c0c0c0"), ("skyblue", "87ceeb"), ("slateblue", "6a5acd"),
+    ("slategray", "708090"), ("slategrey", "708090"), ("snow", "fffafa"),
+    ("springgreen", "00ff7f"), ("steelblue", "4682b4"), ("tan", "d2b48c"),
+    ("teal", "008080"), ("thistle", "d8bfd8"), ("tomato", "ff6347"),
+    ("turquoise", "40e0d0"), ("violet", "ee82ee"), ("wheat", "f5deb3"),
+    ("white", "ffffff"), ("whitesmoke", "f5f5f5"), ("yellow", "ffff00"),
+    ("yellowgreen", "9acd32"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgba(input: &str) -> [u8; 4] {
+        parse_color(input).unwrap().0
+    }
+
+    #[test]
+    fn parses_hex() {
+        assert_eq!(rgba("#f80"), [255, 136, 0, 255]);
+        assert_eq!(rgba("#f808"), [255, 136, 0, 136]);
+        assert_eq!(rgba("#1F77B4"), [31, 119, 180, 255]);
+        assert_eq!(rgba(" #1f77b480 "), [31, 119, 180, 128]);
+    }
+
+    #[test]
+    fn parses_rgb() {
+        assert_eq!(rgba("rgb(31, 119, 180)"), [31, 119, 180, 255]);
+        assert_eq!(rgba("RGBA(31,119,180,0.5)"), [31, 119, 180, 128]);
+        assert_eq!(rgba("rgb(31 119 180 / 50%)"), [31, 119, 180, 128]);
+        assert_eq!(rgba("rgb(100%, 0%, 300, 2)"), [255, 0, 255, 255]);
+    }
+
+    #[test]
+    fn parses_hsl() {
+        assert_eq!(rgba("hsl(0, 100%, 50%)"), [255, 0, 0, 255]);
+        assert_eq!(rgba("hsl(120deg 100% 25%)"), [0, 128, 0, 255]);
+        assert_eq!(rgba("hsla(240, 100%, 50%, 0.25)"), [0, 0, 255, 64]);
+        assert_eq!(rgba("hsl(-120, 100%, 50% / 100%)"), [0, 0, 255, 255]);
+        assert_eq!(rgba("hsl(0, 0%, 100%)"), [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn parses_named_colours() {
+        assert_eq!(rgba("rebeccapurple"), [102, 51, 153, 255]);
+        assert_eq!(rgba("Gray"), rgba("grey"));
+        assert_eq!(rgba("transparent"), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        for input in [
+            "", "#", "#12", "#12345", "#1234567", "#ggg", "#+1f", "#ééé",
+            "rgb(1, 2)", "rgb(1, 2, 3, 4, 5)", "rgb(a, b, c)", "rgb(1, 2, 3", "cmyk(0, 0, 0, 0)",
+            "hsl(red, 100%, 50%)", "hsl(0, 100%)", "notacolour", "1f77b4",
+        ] {
+            assert!(parse_color(input).is_err(), "{:?} parsed", input);
+        }
+    }
+
+    #[test]
+    fn formats_and_darkens() {
+        assert_eq!(to_hex(Rgba([31, 119, 180, 255])), "#1f77b4");
+        assert_eq!(to_hex(Rgba([31, 119, 180, 128])), "#1f77b480");
+        assert_eq!(darken(Rgba([200, 100, 50, 128]), 0.5).0, [100, 50, 25, 128]);
+        assert_eq!(darken(Rgba([200, 100, 50, 255]), 2.0).0, [0, 0, 0, 255]);
+    }
+}
@@ -3,6 +3,8 @@ use serde::Deserialize;
 use std::path::{Path, PathBuf};
 use std::fs;
 use anyhow::{Context, Result};
+use crate::color;
+use crate::expr::Expr;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct AppConfig {
@@ -18,30 +20,331 @@ pub struct InputConfig {
     pub data_csv: PathBuf,
     pub join_column_shape: String,
     pub join_column_csv: String,
+    // Boundary attribute holding each area's name, returned by the API and searched by /api/search
+    pub name_column: Option<String>,
+    // Further boundary attributes to keep (county, ED name...), returned by the API and searchable
+    #[serde(default)]
+    pub attributes: Vec<String>,
     pub water_mask: Option<PathBuf>, // Added for water masking
+    // Source CRS of the boundaries, e.g. "EPSG:2157". Falls back to the .prj / GeoJSON crs, then WGS84.
+    pub crs: Option<String>,
+    pub water_mask_crs: Option<String>,
+    // Ancillary layer (buildings, land use) steering where dots go within each area
+    pub placement: Option<PlacementConfig>,
+    // Further mask layers (airports, industrial zones, parks...), applied alongside the two above
+    #[serde(default)]
+    pub masks: Vec<MaskConfig>,
+    #[serde(default)]
+    pub join_policy: JoinPolicy,
+    // Cell values (e.g. "*" or "..") read as 0 instead of failing the numeric parse
+    #[serde(default)]
+    pub suppression_markers: Vec<String>,
+    // Extra geography levels (Electoral Division, LEA, county...) alongside the one above
+    #[serde(default)]
+    pub levels: HashMap<String, LevelConfig>,
+}
+
+/// A polygon layer that constrains or weights dot placement (dasymetric mapping).
+/// Shorthand for a `constrain` or `weight` mask named "placement".
+#[derive(Debug, Deserialize, Clone)]
+pub struct PlacementConfig {
+    pub path: PathBuf, // GeoJSON FeatureCollection or shapefile
+    pub crs: Option<String>,
+    #[serde(default)]
+    pub mode: PlacementMode,
+    // Numeric feature property holding each polygon's weight (e.g. dwellings or a land-use factor)
+    pub weight_property: Option<String>,
+    #[serde(default = "default_weight")]
+    pub default_weight: f64,
+    // `weight` mode: density of the rest of the area, relative to the layer's weights
+    #[serde(default = "default_weight")]
+    pub background_weight: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlacementMode {
+    // Dots only inside the layer's polygons (areas with none fall back to uniform placement)
+    #[default]
+    Constrain,
+    // Dots anywhere, but denser inside the layer's polygons
+    Weight,
+}
+
+/// A named polygon layer that removes land from the areas or steers dot placement within them.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MaskConfig {
+    pub name: String,
+    pub path: PathBuf, // GeoJSON FeatureCollection or shapefile
+    pub crs: Option<String>,
+    #[serde(default)]
+    pub mode: MaskMode,
+    pub weight_property: Option<String>,
+    #[serde(default = "default_weight")]
+    pub default_weight: f64,
+    #[serde(default = "default_weight")]
+    pub background_weight: f64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MaskMode {
+    // Cut the polygons out of the areas (water, airports)
+    #[default]
+    Subtract,
+    // Dots only inside the polygons
+    Constrain,
+    // Dots denser inside the polygons, by weight
+    Weight,
+}
+
+/// Name given to `input.water_mask` in the mask list.
+pub const WATER_MASK: &str = "water";
+/// Name given to `input.placement` in the mask list.
+pub const PLACEMENT_MASK: &str = "placement";
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+/// Name of the geography level described directly by `[input]`.
+pub const DEFAULT_LEVEL: &str = "SmallArea";
+
+/// Property prefix of boundary attributes in GeoJSON exports; reserved as a dataset name.
+pub const ATTRIBUTE_PREFIX: &str = "attr";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LevelConfig {
+    // Position from finest to coarsest; `SmallArea` is 0, so extra levels start at 1
+    pub rank: u32,
+    pub shapefile: PathBuf,
+    pub join_column_shape: String,
+    pub name_column: Option<String>,
+    #[serde(default)]
+    pub attributes: Vec<String>,
+    // Default to input.data_csv / input.join_column_csv
+    pub data_csv: Option<PathBuf>,
+    pub join_column_csv: Option<String>,
+    pub crs: Option<String>,
+}
+
+/// A geography level with its defaults from `[input]` filled in.
+#[derive(Debug, Clone)]
+pub struct GeographyLevel {
+    pub name: String,
+    pub shapefile: PathBuf,
+    pub join_column_shape: String,
+    pub data_csv: PathBuf,
+    pub join_column_csv: String,
+    pub crs: Option<String>,
+    pub name_column: Option<String>,
+    pub attributes: Vec<String>,
+}
+
+/// What to do when CSV rows and boundary features don't line up one-to-one.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JoinPolicy {
+    #[default]
+    Warn,
+    Error,
+    Ignore,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ProcessingConfig {
     pub datasets: HashMap<String, DatasetConfig>,
+    // Areas that subtractive masks would shrink below this fraction (0-1) of their original
+    // area keep their unmasked boundary instead. Fully erased areas always do.
+    #[serde(default)]
+    pub min_retained_fraction: f64,
+    // Seed for dot placement; the same seed and inputs always give the same dots
+    #[serde(default)]
+    pub seed: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct DatasetConfig {
     pub categories: Vec<CategoryConfig>,
     pub not_stated: Option<NotStatedConfig>,
+    // Optional per-dataset CSV; defaults to input.data_csv / input.join_column_csv
+    pub data_csv: Option<PathBuf>,
+    pub join_column_csv: Option<String>,
+    // Geography level the dataset is mapped at; defaults to DEFAULT_LEVEL
+    pub level: Option<String>,
+    // Names of the mask layers that apply to this dataset; all of them when omitted
+    pub masks: Option<Vec<String>>,
+    // Earlier censuses to compare against: one `[...compare]` table or several `[[...compare]]`
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub compare: Vec<CompareConfig>,
+}
+
+impl DatasetConfig {
+    pub fn level(&self) -> &str {
+        self.level.as_deref().unwrap_or(DEFAULT_LEVEL)
+    }
+
+    /// Tile layer of gains and losses since `compare.previous_year`: "{dataset}_change", with
+    /// the year appended when the dataset is compared with more than one census.
+    pub fn change_layer_name(&self, dataset_name: &str, compare: &CompareConfig) -> String {
+        if self.compare.len() == 1 {
+            format!("{}{}", dataset_name, CHANGE_LAYER_SUFFIX)
+        } else {
+            format!("{}{}_{}", dataset_name, CHANGE_LAYER_SUFFIX, compare.previous_year)
+        }
+    }
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(one) => vec![one],
+        OneOrMany::Many(many) => many,
+    })
+}
+
+/// Which census a column or value belongs to when a dataset is compared across years.
+/// `Previous` covers every earlier census; they share the `previous_*` column overrides.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Vintage {
+    Current,
+    Previous,
+}
+
+/// An earlier census vintage for a dataset, mapped onto the current boundaries.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CompareConfig {
+    pub year: String,          // Label for the dataset's own census, e.g. "2022"
+    pub previous_year: String, // e.g. "2016"
+    pub data_csv: PathBuf,     // CSV of the earlier census
+    pub join_column_csv: Option<String>, // Defaults to the dataset level's join column
+    // Without a mapping, ids are assumed unchanged between the two boundary versions
+    pub mapping: Option<BoundaryMappingConfig>,
+    // Render a "{dataset}_change" tile layer of gains and losses
+    #[serde(default = "default_true")]
+    pub change_layer: bool,
+}
+
+/// A correspondence table from earlier boundary ids to current ones.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BoundaryMappingConfig {
+    pub csv: PathBuf,
+    pub from_column: String,
+    pub to_column: String,
+    // Share of the earlier area assigned to the current one; split evenly when absent
+    pub weight_column: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// Suffix of the tile layer holding a dataset's gains and losses between census years.
+pub const CHANGE_LAYER_SUFFIX: &str = "_change";
+
+/// A tile set rendered by `generate`: either a dataset or its change layer.
+#[derive(Debug, Clone)]
+pub struct TileLayer {
+    pub name: String,
+    pub dataset: String,
+    pub categories: Vec<LayerCategory>,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerCategory {
+    pub name: String,
+    pub color: String,
+}
+
+pub fn gain_category(category: &str) -> String {
+    format!("{} gain", category)
+}
+
+pub fn loss_category(category: &str) -> String {
+    format!("{} loss", category)
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct CategoryConfig {
     pub name: String,
-    pub color: String, // Hex code
+    pub color: String, // Hex, CSS named colour, rgb() or hsl()
+    #[serde(default)]
     pub columns: Vec<String>, // CSV columns to sum
+    pub expression: Option<String>, // Alternative to columns, e.g. "T2_2ALL - T2_2WI"
+    // Columns / expression in the earlier census when they differ from the current ones
+    pub previous_columns: Option<Vec<String>>,
+    pub previous_expression: Option<String>,
+    // Colour of loss dots on the change layer; defaults to a darker shade of `color`
+    pub loss_color: Option<String>,
+}
+
+impl CategoryConfig {
+    pub fn columns_for(&self, vintage: Vintage) -> &[String] {
+        match vintage {
+            Vintage::Previous if self.has_previous_override() => {
+                self.previous_columns.as_deref().unwrap_or_default()
+            }
+            _ => &self.columns,
+        }
+    }
+
+    pub fn expression_for(&self, vintage: Vintage) -> Option<&str> {
+        match vintage {
+            Vintage::Previous if self.has_previous_override() => self.previous_expression.as_deref(),
+            _ => self.expression.as_deref(),
+        }
+    }
+
+    fn has_previous_override(&self) -> bool {
+        self.previous_columns.is_some() || self.previous_expression.is_some()
+    }
+
+    /// The parsed expression, if this category is computed rather than a plain column sum.
+    pub fn parsed_expression(&self, vintage: Vintage) -> Result<Option<Expr>> {
+        self.expression_for(vintage).map(Expr::parse).transpose()
+    }
+
+    /// Every CSV column this category reads (expression columns are skipped if it doesn't parse).
+    pub fn source_columns(&self, vintage: Vintage) -> Vec<String> {
+        let mut cols = self.columns_for(vintage).to_vec();
+        if let Ok(Some(expr)) = self.parsed_expression(vintage) {
+            cols.extend(expr.columns().into_iter().map(String::from));
+        }
+        cols
+    }
+
+    pub fn loss_color(&self) -> String {
+        match &self.loss_color {
+            Some(color) => color.clone(),
+            None => color::parse_color(&self.color)
+                .map(|rgba| color::to_hex(color::darken(rgba, 0.5)))
+                .unwrap_or_else(|_| self.color.clone()),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct NotStatedConfig {
     pub column: String,
+    pub previous_column: Option<String>,
+}
+
+impl NotStatedConfig {
+    pub fn column_for(&self, vintage: Vintage) -> &str {
+        match (vintage, &self.previous_column) {
+            (Vintage::Previous, Some(column)) => column,
+            _ => &self.column,
+        }
+    }
 }
 
 
@@ -50,19 +353,270 @@ pub struct OutputConfig {
     pub tile_dir: PathBuf,
     pub min_zoom: u8,
     pub max_zoom: u8,
+    // Masked geometries are cached here, keyed by hashes of the boundary and mask inputs
+    #[serde(default = "default_cache_dir")]
+    pub cache_dir: PathBuf,
+    #[serde(default = "default_true")]
+    pub mask_cache: bool,
+}
+
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from("output/cache")
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ServerConfig {
     pub port: u16,
+    // Answer /api/query against the masked geometry dots were drawn in, not the raw boundaries
+    #[serde(default)]
+    pub masked_geometry: bool,
+    // Render tiles missing from tile_dir on request, from dots generated at startup
+    #[serde(default)]
+    pub dynamic_tiles: bool,
+    // Highest zoom dynamic tiles are rendered at (may exceed output.max_zoom)
+    #[serde(default = "default_dynamic_max_zoom")]
+    pub dynamic_max_zoom: u8,
+    // Rendered tiles kept in memory, least recently used evicted first
+    #[serde(default = "default_tile_cache_size")]
+    pub tile_cache_size: usize,
+    // Also keep rendered tiles on disk here, across restarts
+    pub tile_cache_dir: Option<PathBuf>,
+    // Boundary attribute (listed in `attributes`) naming each area's county, for county averages
+    pub county_attribute: Option<String>,
+}
+
+fn default_dynamic_max_zoom() -> u8 {
+    18
+}
+
+fn default_tile_cache_size() -> usize {
+    1024
 }
 
 impl AppConfig {
     pub fn load_from_file(path: &Path) -> Result<Self> {
+        let config = Self::parse_file(path)?;
+        config.check_categories()?;
+        config.check_levels()?;
+        config.check_masks()?;
+        config.check_comparisons()?;
+        Ok(config)
+    }
+
+    /// Reads and deserializes the TOML without any further checks.
+    pub fn parse_file(path: &Path) -> Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read config file: {:?}", path))?;
         let config: AppConfig = toml::from_str(&content)
             .with_context(|| "Failed to parse TOML configuration")?;
         Ok(config)
     }
+
+    /// Every geography level, finest first: the `[input]` one, then `[input.levels]` by rank.
+    pub fn geography_levels(&self) -> Vec<GeographyLevel> {
+        let input = &self.input;
+        let mut levels = vec![GeographyLevel {
+            name: DEFAULT_LEVEL.to_string(),
+            shapefile: input.shapefile.clone(),
+            join_column_shape: input.join_column_shape.clone(),
+            data_csv: input.data_csv.clone(),
+            join_column_csv: input.join_column_csv.clone(),
+            crs: input.crs.clone(),
+            name_column: input.name_column.clone(),
+            attributes: input.attributes.clone(),
+        }];
+
+        let mut names: Vec<&String> = input.levels.keys().collect();
+        names.sort_by_key(|name| (input.levels[*name].rank, *name));
+        for name in names {
+            let level = &input.levels[name];
+            levels.push(GeographyLevel {
+                name: name.clone(),
+                shapefile: level.shapefile.clone(),
+                join_column_shape: level.join_column_shape.clone(),
+                data_csv: level.data_csv.clone().unwrap_or_else(|| input.data_csv.clone()),
+                join_column_csv: level.join_column_csv.clone().unwrap_or_else(|| input.join_column_csv.clone()),
+                crs: level.crs.clone(),
+                name_column: level.name_column.clone(),
+                attributes: level.attributes.clone(),
+            });
+        }
+        levels
+    }
+
+    /// Every mask layer: `input.water_mask` and `input.placement` first, then `input.masks`.
+    pub fn masks(&self) -> Vec<MaskConfig> {
+        let input = &self.input;
+        let mut masks = Vec::new();
+        if let Some(path) = &input.water_mask {
+            masks.push(MaskConfig {
+                name: WATER_MASK.to_string(),
+                path: path.clone(),
+                crs: input.water_mask_crs.clone(),
+                mode: MaskMode::Subtract,
+                weight_property: None,
+                default_weight: default_weight(),
+                background_weight: default_weight(),
+            });
+        }
+        if let Some(placement) = &input.placement {
+            masks.push(MaskConfig {
+                name: PLACEMENT_MASK.to_string(),
+                path: placement.path.clone(),
+                crs: placement.crs.clone(),
+                mode: match placement.mode {
+                    PlacementMode::Constrain => MaskMode::Constrain,
+                    PlacementMode::Weight => MaskMode::Weight,
+                },
+                weight_property: placement.weight_property.clone(),
+                default_weight: placement.default_weight,
+                background_weight: placement.background_weight,
+            });
+        }
+        masks.extend(input.masks.iter().cloned());
+        masks
+    }
+
+    /// Sorted names of the masks applied to a dataset.
+    pub fn dataset_masks(&self, dataset_name: &str) -> Vec<String> {
+        let mut names: Vec<String> = match self.processing.datasets.get(dataset_name).and_then(|d| d.masks.as_ref()) {
+            Some(names) => names.clone(),
+            None => self.masks().into_iter().map(|m| m.name).collect(),
+        };
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Identifies a set of masks, so datasets sharing the same masks share one masked geometry.
+    pub fn mask_key(&self, dataset_name: &str) -> String {
+        self.dataset_masks(dataset_name).join("+")
+    }
+
+    pub fn check_masks(&self) -> Result<()> {
+        let fraction = self.processing.min_retained_fraction;
+        if !(0.0..=1.0).contains(&fraction) {
+            anyhow::bail!("processing.min_retained_fraction must be between 0 and 1, got {}", fraction);
+        }
+        let masks = self.masks();
+        let mut names = std::collections::HashSet::new();
+        for mask in &masks {
+            // '+' joins mask names in `mask_key`
+            if mask.name.is_empty() || mask.name.contains('+') {
+                anyhow::bail!("Mask name '{}' must be non-empty and must not contain '+'", mask.name);
+            }
+            if !names.insert(mask.name.as_str()) {
+                anyhow::bail!("Mask name '{}' is used more than once", mask.name);
+            }
+            for (field, weight) in [("default_weight", mask.default_weight), ("background_weight", mask.background_weight)] {
+                if !(weight.is_finite() && weight >= 0.0) {
+                    anyhow::bail!("Mask '{}': {} must be a non-negative number, got {}", mask.name, field, weight);
+                }
+            }
+        }
+        for (dataset_name, dataset) in &self.processing.datasets {
+            for name in dataset.masks.iter().flatten() {
+                if !names.contains(name.as_str()) {
+                    anyhow::bail!("Dataset '{}' uses unknown mask '{}'", dataset_name, name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tile layers to render: one per dataset, plus a change layer for compared datasets.
+    pub fn tile_layers(&self) -> Vec<TileLayer> {
+        let mut names: Vec<&String> = self.processing.datasets.keys().collect();
+        names.sort();
+
+        let mut layers = Vec::new();
+        for name in names {
+            let dataset = &self.processing.datasets[name];
+            layers.push(TileLayer {
+                name: name.clone(),
+                dataset: name.clone(),
+                categories: dataset.categories.iter()
+                    .map(|c| LayerCategory { name: c.name.clone(), color: c.color.clone() })
+                    .collect(),
+            });
+
+            for compare in dataset.compare.iter().filter(|c| c.change_layer) {
+                let mut categories = Vec::new();
+                for c in &dataset.categories {
+                    categories.push(LayerCategory { name: gain_category(&c.name), color: c.color.clone() });
+                    categories.push(LayerCategory { name: loss_category(&c.name), color: c.loss_color() });
+                }
+                layers.push(TileLayer {
+                    name: dataset.change_layer_name(name, compare),
+                    dataset: name.clone(),
+                    categories,
+                });
+            }
+        }
+        layers
+    }
+
+    pub fn check_levels(&self) -> Result<()> {
+        if self.input.levels.contains_key(DEFAULT_LEVEL) {
+            anyhow::bail!("Level name '{}' is reserved for the [input] boundaries", DEFAULT_LEVEL);
+        }
+        let mut ranks: HashMap<u32, &String> = HashMap::new();
+        for (name, level) in &self.input.levels {
+            if level.rank == 0 {
+                anyhow::bail!("Level '{}': rank must be at least 1 (0 is the [input] boundaries)", name);
+            }
+            if let Some(other) = ranks.insert(level.rank, name) {
+                anyhow::bail!("Levels '{}' and '{}' share rank {}", other, name, level.rank);
+            }
+        }
+        for (dataset_name, dataset) in &self.processing.datasets {
+            let level = dataset.level();
+            if level != DEFAULT_LEVEL && !self.input.levels.contains_key(level) {
+                anyhow::bail!("Dataset '{}' uses unknown geography level '{}'", dataset_name, level);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_comparisons(&self) -> Result<()> {
+        for (dataset_name, dataset) in &self.processing.datasets {
+            let mut years = std::collections::HashSet::new();
+            for compare in &dataset.compare {
+                let year = compare.previous_year.trim();
+                if year.is_empty() || !year.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                    anyhow::bail!("Dataset '{}': previous_year '{}' must be letters, digits, '-' or '_'", dataset_name, compare.previous_year);
+                }
+                if !years.insert(year) {
+                    anyhow::bail!("Dataset '{}' compares with {} more than once", dataset_name, year);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_categories(&self) -> Result<()> {
+        for (dataset_name, dataset) in &self.processing.datasets {
+            if dataset_name == ATTRIBUTE_PREFIX {
+                anyhow::bail!("Dataset name '{}' is reserved for exported attributes", ATTRIBUTE_PREFIX);
+            }
+            for category in &dataset.categories {
+                let context = || format!("Dataset '{}', category '{}'", dataset_name, category.name);
+                color::parse_color(&category.color)
+                    .with_context(|| format!("{} has an invalid color", context()))?;
+                color::parse_color(&category.loss_color())
+                    .with_context(|| format!("{} has an invalid loss_color", context()))?;
+                category.parsed_expression(Vintage::Current)
+                    .with_context(|| format!("{} has an invalid expression", context()))?;
+                category.parsed_expression(Vintage::Previous)
+                    .with_context(|| format!("{} has an invalid previous_expression", context()))?;
+                if category.columns.is_empty() == category.expression.is_none() {
+                    anyhow::bail!("{} must set exactly one of `columns` or `expression`", context());
+                }
+                if category.previous_columns.is_some() && category.previous_expression.is_some() {
+                    anyhow::bail!("{} sets both `previous_columns` and `previous_expression`", context());
+                }
+            }
+        }
+        Ok(())
+    }
 }
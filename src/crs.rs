@@ -0,0 +1,341 @@
+use anyhow::{Context, Result, anyhow};
+use geo::{Coord, MapCoordsInPlace, MultiPolygon};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::Path;
+
+/// Coordinate reference systems we can bring into WGS84 lon/lat.
+///
+/// Everything downstream (tiling, the API lookup) works in degrees, so any
+/// boundary or mask file in a projected CRS is reprojected as it is loaded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Crs {
+    /// EPSG:4326 (also accepted for ETRS89/IRENET95 geographic, which differ by < 1m)
+    Wgs84,
+    /// EPSG:2157 - Irish Transverse Mercator on IRENET95/ETRS89
+    IrishTransverseMercator,
+    /// EPSG:29903 - TM75 Irish Grid on the Airy Modified 1849 ellipsoid
+    IrishGrid,
+    /// EPSG:3857 - Web Mercator / Pseudo-Mercator
+    WebMercator,
+}
+
+// Ellipsoids
+const GRS80_A: f64 = 6378137.0;
+const GRS80_F: f64 = 1.0 / 298.257222101;
+const AIRY_MOD_A: f64 = 6377340.189;
+const AIRY_MOD_F: f64 = 1.0 / 299.3249646;
+
+// TM75 -> WGS84 (EPSG:1954), position vector convention: metres, arc-seconds, ppm
+const TM75_TO_WGS84: [f64; 7] = [482.5, -130.6, 564.6, -1.042, -0.214, -0.631, 8.15];
+
+struct TransverseMercator {
+    a: f64,
+    f: f64,
+    lat0: f64,
+    lon0: f64,
+    k0: f64,
+    false_easting: f64,
+    false_northing: f64,
+}
+
+const ITM: TransverseMercator = TransverseMercator {
+    a: GRS80_A,
+    f: GRS80_F,
+    lat0: 53.5,
+    lon0: -8.0,
+    k0: 0.99982,
+    false_easting: 600000.0,
+    false_northing: 750000.0,
+};
+
+const IRISH_GRID: TransverseMercator = TransverseMercator {
+    a: AIRY_MOD_A,
+    f: AIRY_MOD_F,
+    lat0: 53.5,
+    lon0: -8.0,
+    k0: 1.000035,
+    false_easting: 200000.0,
+    false_northing: 250000.0,
+};
+
+impl Crs {
+    /// Parses a user supplied CRS name such as `EPSG:2157`, `ITM` or `urn:ogc:def:crs:EPSG::29903`.
+    pub fn from_name(name: &str) -> Result<Self> {
+        let upper = name.trim().to_uppercase();
+        // Accept EPSG:XXXX, EPSG::XXXX, urn:ogc:def:crs:EPSG::XXXX and bare codes
+        let code = upper.rsplit(':').next().unwrap_or("");
+
+        match code {
+            "4326" | "4258" | "WGS84" | "CRS84" | "ETRS89" => Ok(Crs::Wgs84),
+            "2157" | "ITM" => Ok(Crs::IrishTransverseMercator),
+            // Not 29902: TM65 Irish Grid is on a different datum
+            "29903" | "IG" | "IRISH_GRID" => Ok(Crs::IrishGrid),
+            "3857" | "900913" | "3785" | "WEBMERCATOR" => Ok(Crs::WebMercator),
+            _ => Err(anyhow!(
+                "Unsupported CRS '{}'. Supported: EPSG:4326, EPSG:2157 (ITM), EPSG:29903 (Irish Grid), EPSG:3857 (Web Mercator)",
+                name
+            )),
+        }
+    }
+
+    /// Detects the CRS from the WKT in an ESRI `.prj` file.
+    pub fn from_prj_wkt(wkt: &str) -> Result<Self> {
+        let normalised: String = wkt.chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '[')
+            .collect::<String>()
+            .to_lowercase();
+
+        if !normalised.starts_with("projcs[") {
+            // A bare GEOGCS: WGS84, ETRS89 and IRENET95 are all close enough to treat as lon/lat
+            if normalised.starts_with("geogcs[") {
+                return Ok(Crs::Wgs84);
+            }
+            return Err(anyhow!("Unrecognised .prj contents (expected PROJCS or GEOGCS)"));
+        }
+
+        if normalised.contains("tm65") {
+            Err(anyhow!("TM65 Irish Grid (EPSG:29902) is not supported; only TM75 (EPSG:29903) is"))
+        } else if normalised.contains("irishtransversemercator") || normalised.contains("irenet95") {
+            Ok(Crs::IrishTransverseMercator)
+        } else if normalised.contains("irishgrid") || normalised.contains("irishnationalgrid") {
+            Ok(Crs::IrishGrid)
+        } else if normalised.contains("webmercator") || normalised.contains("pseudomercator") {
+            Ok(Crs::WebMercator)
+        } else {
+            Err(anyhow!("Unsupported projection in .prj file; set the CRS explicitly in config.toml"))
+        }
+    }
+
+    /// Looks for a `.prj` file next to `path` and detects the CRS from it.
+    pub fn from_prj_file(path: &Path) -> Result<Option<Self>> {
+        let prj_path = path.with_extension("prj");
+        if !prj_path.exists() {
+            return Ok(None);
+        }
+        let wkt = fs::read_to_string(&prj_path)
+            .with_context(|| format!("Failed to read projection file: {:?}", prj_path))?;
+        Crs::from_prj_wkt(&wkt)
+            .with_context(|| format!("Failed to detect CRS from {:?}", prj_path))
+            .map(Some)
+    }
+
+    /// Reads the legacy (pre RFC 7946) `crs` member of a GeoJSON FeatureCollection.
+    pub fn from_geojson_members(members: Option<&geojson::JsonObject>) -> Result<Option<Self>> {
+        let name = members
+            .and_then(|m| m.get("crs"))
+            .and_then(|crs| crs.get("properties"))
+            .and_then(|props| props.get("name"))
+            .and_then(|name| name.as_str());
+
+        match name {
+            Some(name) => Crs::from_name(name).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolves the CRS of a boundary or mask file: an explicit config value wins,
+    /// then a `.prj` next to the file, otherwise WGS84 is assumed.
+    pub fn resolve(configured: Option<&str>, path: &Path) -> Result<Self> {
+        if let Some(name) = configured {
+            return Crs::from_name(name);
+        }
+        Ok(Crs::from_prj_file(path)?.unwrap_or(Crs::Wgs84))
+    }
+
+    pub fn is_wgs84(&self) -> bool {
+        *self == Crs::Wgs84
+    }
+
+    /// Converts a single coordinate into WGS84 (x = lon, y = lat).
+    pub fn to_wgs84(&self, coord: Coord<f64>) -> Coord<f64> {
+        match self {
+            Crs::Wgs84 => coord,
+            Crs::IrishTransverseMercator => {
+                // IRENET95 is ETRS89 based, which is within a metre of WGS84
+                let (lat, lon) = ITM.inverse(coord.x, coord.y);
+                Coord { x: lon, y: lat }
+            }
+            Crs::IrishGrid => {
+                let (lat, lon) = IRISH_GRID.inverse(coord.x, coord.y);
+                let (lat, lon) = helmert_to_wgs84(lat, lon, AIRY_MOD_A, AIRY_MOD_F, &TM75_TO_WGS84);
+                Coord { x: lon, y: lat }
+            }
+            Crs::WebMercator => {
+                let lon = (coord.x / GRS80_A).to_degrees();
+                let lat = (2.0 * (coord.y / GRS80_A).exp().atan() - PI / 2.0).to_degrees();
+                Coord { x: lon, y: lat }
+            }
+        }
+    }
+
+    pub fn reproject(&self, geometry: &mut MultiPolygon<f64>) {
+        if self.is_wgs84() {
+            return;
+        }
+        geometry.map_coords_in_place(|c| self.to_wgs84(c));
+    }
+}
+
+impl TransverseMercator {
+    fn third_flattening(&self) -> f64 {
+        self.f / (2.0 - self.f)
+    }
+
+    /// Rectifying radius A of the Krüger series.
+    fn rectifying_radius(&self) -> f64 {
+        let n = self.third_flattening();
+        let n2 = n * n;
+        self.a / (1.0 + n) * (1.0 + n2 / 4.0 + n2 * n2 / 64.0)
+    }
+
+    /// Scaled northing of the latitude of origin on the central meridian.
+    fn origin_xi(&self) -> f64 {
+        let n = self.third_flattening();
+        let e = (self.f * (2.0 - self.f)).sqrt();
+        let alpha = [
+            n / 2.0 - 2.0 / 3.0 * n.powi(2) + 5.0 / 16.0 * n.powi(3),
+            13.0 / 48.0 * n.powi(2) - 3.0 / 5.0 * n.powi(3),
+            61.0 / 240.0 * n.powi(3),
+        ];
+
+        let phi = self.lat0.to_radians();
+        let t = (phi.sin().atanh() - e * (e * phi.sin()).atanh()).sinh();
+        let xi_prime = t.atan();
+
+        xi_prime + alpha.iter().enumerate()
+            .map(|(j, a)| a * (2.0 * (j + 1) as f64 * xi_prime).sin())
+            .sum::<f64>()
+    }
+
+    /// Projected easting/northing to geodetic (lat, lon) degrees on this ellipsoid.
+    fn inverse(&self, easting: f64, northing: f64) -> (f64, f64) {
+        let n = self.third_flattening();
+        let big_a = self.rectifying_radius();
+        let beta = [
+            n / 2.0 - 2.0 / 3.0 * n.powi(2) + 37.0 / 96.0 * n.powi(3),
+            1.0 / 48.0 * n.powi(2) + 1.0 / 15.0 * n.powi(3),
+            17.0 / 480.0 * n.powi(3),
+        ];
+        let delta = [
+            2.0 * n - 2.0 / 3.0 * n.powi(2) - 2.0 * n.powi(3),
+            7.0 / 3.0 * n.powi(2) - 8.0 / 5.0 * n.powi(3),
+            56.0 / 15.0 * n.powi(3),
+        ];
+
+        let xi = (northing - self.false_northing) / (self.k0 * big_a) + self.origin_xi();
+        let eta = (easting - self.false_easting) / (self.k0 * big_a);
+
+        let mut xi_prime = xi;
+        let mut eta_prime = eta;
+        for (j, b) in beta.iter().enumerate() {
+            let k = 2.0 * (j + 1) as f64;
+            xi_prime -= b * (k * xi).sin() * (k * eta).cosh();
+            eta_prime -= b * (k * xi).cos() * (k * eta).sinh();
+        }
+
+        let chi = (xi_prime.sin() / eta_prime.cosh()).asin();
+        let phi = chi + delta.iter().enumerate()
+            .map(|(j, d)| d * (2.0 * (j + 1) as f64 * chi).sin())
+            .sum::<f64>();
+        let lambda = self.lon0.to_radians() + eta_prime.sinh().atan2(xi_prime.cos());
+
+        (phi.to_degrees(), lambda.to_degrees())
+    }
+}
+
+/// Shifts a geodetic position from a local datum to WGS84 via a 7-parameter Helmert transform.
+fn helmert_to_wgs84(lat: f64, lon: f64, a: f64, f: f64, params: &[f64; 7]) -> (f64, f64) {
+    let [tx, ty, tz, rx, ry, rz, s] = *params;
+    let arcsec = PI / (180.0 * 3600.0);
+    let (rx, ry, rz) = (rx * arcsec, ry * arcsec, rz * arcsec);
+    let scale = 1.0 + s * 1e-6;
+
+    let (x, y, z) = geodetic_to_ecef(lat, lon, a, f);
+    let x2 = tx + scale * (x - rz * y + ry * z);
+    let y2 = ty + scale * (rz * x + y - rx * z);
+    let z2 = tz + scale * (-ry * x + rx * y + z);
+
+    ecef_to_geodetic(x2, y2, z2, GRS80_A, 1.0 / 298.257223563)
+}
+
+fn geodetic_to_ecef(lat: f64, lon: f64, a: f64, f: f64) -> (f64, f64, f64) {
+    let e2 = f * (2.0 - f);
+    let (phi, lambda) = (lat.to_radians(), lon.to_radians());
+    let nu = a / (1.0 - e2 * phi.sin().powi(2)).sqrt();
+    (
+        nu * phi.cos() * lambda.cos(),
+        nu * phi.cos() * lambda.sin(),
+        nu * (1.0 - e2) * phi.sin(),
+    )
+}
+
+fn ecef_to_geodetic(x: f64, y: f64, z: f64, a: f64, f: f64) -> (f64, f64) {
+    let e2 = f * (2.0 - f);
+    let p = (x * x + y * y).sqrt();
+    let lambda = y.atan2(x);
+
+    let mut phi = z.atan2(p * (1.0 - e2));
+    for _ in 0..5 {
+        let nu = a / (1.0 - e2 * phi.sin().powi(2)).sqrt();
+        phi = (z + e2 * nu * phi.sin()).atan2(p);
+    }
+
+    (phi.to_degrees(), lambda.to_degrees())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn projection_origins_invert_to_the_natural_origin() {
+        for tm in [&ITM, &IRISH_GRID] {
+            let (lat, lon) = tm.inverse(tm.false_easting, tm.false_northing);
+            // Within a centimetre
+            assert!((lat - 53.5).abs() < 1e-7, "lat {}", lat);
+            assert!((lon + 8.0).abs() < 1e-7, "lon {}", lon);
+        }
+    }
+
+    #[test]
+    fn tm65_irish_grid_is_rejected() {
+        assert_eq!(Crs::from_name("EPSG:29903").unwrap(), Crs::IrishGrid);
+        assert!(Crs::from_name("EPSG:29902").is_err());
+        assert!(Crs::from_prj_wkt(r#"PROJCS["TM65_Irish_Grid",GEOGCS["GCS_TM65"]]"#).is_err());
+        assert_eq!(Crs::from_prj_wkt(r#"PROJCS["TM75_Irish_Grid",GEOGCS["GCS_TM75"]]"#).unwrap(), Crs::IrishGrid);
+    }
+
+    #[test]
+    fn transverse_mercator_matches_epsg_example() {
+        // EPSG Guidance Note 7-2, Transverse Mercator example (OSGB 1936 / British National Grid):
+        // E 577274.99, N 69740.50 is 50°30'N, 0°30'E
+        let osgb = TransverseMercator {
+            a: 6377563.396,
+            f: 1.0 / 299.3249646,
+            lat0: 49.0,
+            lon0: -2.0,
+            k0: 0.9996012717,
+            false_easting: 400000.0,
+            false_northing: -100000.0,
+        };
+        let (lat, lon) = osgb.inverse(577274.99, 69740.50);
+        // 1e-7 degrees is about a centimetre
+        assert!((lat - 50.5).abs() < 1e-7, "lat {}", lat);
+        assert!((lon - 0.5).abs() < 1e-7, "lon {}", lon);
+    }
+
+    #[test]
+    fn helmert_matches_epsg_example() {
+        // EPSG Guidance Note 7-2, position vector example (WGS 72 to WGS 84): X 3657660.66,
+        // Y 255768.55, Z 5201382.11 becomes X 3657660.78, Y 255778.43, Z 5201387.75
+        let (wgs72_a, wgs72_f) = (6378135.0, 1.0 / 298.26);
+        let (lat, lon) = ecef_to_geodetic(3657660.66, 255768.55, 5201382.11, wgs72_a, wgs72_f);
+        let params = [0.0, 0.0, 4.5, 0.0, 0.0, 0.554, 0.219];
+        let (lat, lon) = helmert_to_wgs84(lat, lon, wgs72_a, wgs72_f, &params);
+        // Heights are dropped along the way, so compare latitude and longitude only
+        let (expected_lat, expected_lon) = ecef_to_geodetic(3657660.78, 255778.43, 5201387.75, GRS80_A, 1.0 / 298.257223563);
+        assert!((lat - expected_lat).abs() < 1e-7, "lat {} vs {}", lat, expected_lat);
+        assert!((lon - expected_lon).abs() < 1e-7, "lon {} vs {}", lon, expected_lon);
+    }
+}
@@ -1,144 +1,699 @@
-use crate::config::AppConfig;
+use crate::config::{AppConfig, BoundaryMappingConfig, GeographyLevel, JoinPolicy, Vintage};
+use crate::crs::Crs;
+use crate::expr::Expr;
 use crate::types::SmallArea;
 use anyhow::{Context, Result, anyhow};
 use csv::ReaderBuilder;
 use geo::MultiPolygon;
 use shapefile::Reader;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
-use std::path::Path;
+use std::path::PathBuf;
+
+// Map<AreaId, Map<DatasetName, Map<Category/NotStated, Count>>>
+type CsvData = HashMap<String, HashMap<String, HashMap<String, u32>>>;
+
+// How many example ids to keep for each kind of join problem
+const JOIN_SAMPLE_LIMIT: usize = 10;
+
+/// A count of ids with a few examples, for reporting join problems.
+#[derive(Debug, Default, Clone)]
+pub struct IdSample {
+    pub count: usize,
+    pub sample: Vec<String>,
+}
+
+impl IdSample {
+    fn push(&mut self, id: &str) {
+        self.count += 1;
+        if self.sample.len() < JOIN_SAMPLE_LIMIT {
+            self.sample.push(id.to_string());
+        }
+    }
+}
+
+/// Summary of how well the CSV rows and boundary features joined.
+#[derive(Debug, Default, Clone)]
+pub struct JoinReport {
+    pub level: String,
+    pub csv_rows: usize,
+    pub features: usize,
+    pub matched: usize,
+    pub null_csv_ids: usize,
+    pub null_feature_ids: usize,
+    pub unmatched_features: IdSample,
+    pub unmatched_csv_rows: IdSample,
+    pub duplicate_csv_ids: IdSample,
+    pub conflicting_csv_ids: IdSample,
+    pub partial_csv_ids: IdSample,
+    pub duplicate_feature_ids: IdSample,
+    // Features whose id matched a CSV row but whose geometry is missing or not a polygon
+    pub non_polygon_features: IdSample,
+    non_polygon_ids: HashSet<String>,
+}
+
+impl JoinReport {
+    /// Problems that fail the run under `join_policy = "error"`. Areas missing from some CSV
+    /// sources are only warned about, as a source may deliberately cover part of the country.
+    pub fn has_problems(&self) -> bool {
+        self.null_csv_ids > 0
+            || self.null_feature_ids > 0
+            || self.unmatched_features.count > 0
+            || self.unmatched_csv_rows.count > 0
+            || self.duplicate_csv_ids.count > 0
+            || self.conflicting_csv_ids.count > 0
+            || self.duplicate_feature_ids.count > 0
+            || self.non_polygon_features.count > 0
+    }
+
+    /// Human readable lines describing each problem found.
+    pub fn problems(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let describe = |label: &str, ids: &IdSample| {
+            format!("{} {} (e.g. {})", ids.count, label, ids.sample.join(", "))
+        };
+
+        if self.unmatched_features.count > 0 {
+            lines.push(describe("boundary features have no matching CSV row", &self.unmatched_features));
+        }
+        if self.unmatched_csv_rows.count > 0 {
+            lines.push(describe("CSV rows have no matching boundary feature", &self.unmatched_csv_rows));
+        }
+        if self.duplicate_csv_ids.count > 0 {
+            lines.push(describe("ids repeated in a CSV with identical values", &self.duplicate_csv_ids));
+        }
+        if self.conflicting_csv_ids.count > 0 {
+            lines.push(describe("ids with conflicting values, repeated in a CSV or across sources (last one wins)", &self.conflicting_csv_ids));
+        }
+        if self.partial_csv_ids.count > 0 {
+            lines.push(describe("areas missing from at least one CSV source (warning only)", &self.partial_csv_ids));
+        }
+        if self.duplicate_feature_ids.count > 0 {
+            lines.push(describe("duplicate ids in the boundary file", &self.duplicate_feature_ids));
+        }
+        if self.non_polygon_features.count > 0 {
+            lines.push(describe("boundary features matched a CSV row but have no polygon geometry", &self.non_polygon_features));
+        }
+        if self.null_csv_ids > 0 {
+            lines.push(format!("{} CSV rows have an empty join id", self.null_csv_ids));
+        }
+        if self.null_feature_ids > 0 {
+            lines.push(format!("{} boundary features have no join id", self.null_feature_ids));
+        }
+        lines
+    }
+
+    fn push_non_polygon(&mut self, id: &str) {
+        self.non_polygon_features.push(id);
+        self.non_polygon_ids.insert(id.to_string());
+    }
+
+    /// Prints the report and, with `join_policy = "error"`, fails on any problem.
+    fn apply_policy(&self, policy: JoinPolicy) -> Result<()> {
+        match policy {
+            JoinPolicy::Ignore => {}
+            JoinPolicy::Warn => self.print(),
+            JoinPolicy::Error => {
+                self.print();
+                if self.has_problems() {
+                    return Err(anyhow!("Join problems found for '{}' and join_policy = \"error\"", self.level));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn print(&self) {
+        println!(
+            "Join report ({}): {} boundary features, {} CSV rows, {} matched",
+            self.level, self.features, self.csv_rows, self.matched
+        );
+        for line in self.problems() {
+            println!("  WARNING: {}", line);
+        }
+    }
+}
 
 pub fn load_data(config: &AppConfig) -> Result<Vec<SmallArea>> {
     println!("Loading data...");
+    let sources = csv_sources(config);
+    let mut small_areas = Vec::new();
+
+    for level in config.geography_levels() {
+        let level_sources: Vec<&CsvSource> = sources.iter().filter(|s| s.level == level.name).collect();
+        if level_sources.is_empty() {
+            // No dataset is mapped at this level
+            continue;
+        }
+        small_areas.extend(load_level(config, &level, &level_sources)?);
+    }
+
+    attach_comparisons(config, &mut small_areas)?;
+
+    Ok(small_areas)
+}
+
+/// Loads one geography level: its CSV sources, its boundaries, and the join between them.
+fn load_level(config: &AppConfig, level: &GeographyLevel, sources: &[&CsvSource]) -> Result<Vec<SmallArea>> {
+    println!("Loading geography level '{}'...", level.name);
+    let mut report = JoinReport {
+        level: level.name.clone(),
+        ..Default::default()
+    };
 
     // 1. Load CSV Data
-    let csv_data = load_csv_data(config)?;
+    let csv_data = load_csv_data(config, sources, &mut report)?;
     println!("Loaded CSV data for {} areas", csv_data.len());
 
     // 2. Load Geometry (Shapefile or GeoJSON)
-    let extension = config.input.shapefile.extension()
+    let extension = level.shapefile.extension()
         .and_then(|e| e.to_str())
         .map(|s: &str| s.to_lowercase())
         .ok_or_else(|| anyhow!("Input geometry file has no extension"))?;
 
     let small_areas = match extension.as_str() {
-        "shp" => load_shapefile_and_join(config, &csv_data)?,
-        "json" | "geojson" => load_geojson_and_join(config, &csv_data)?,
+        "shp" => load_shapefile_and_join(level, &csv_data, &mut report)?,
+        "json" | "geojson" => load_geojson_and_join(level, &csv_data, &mut report)?,
         _ => return Err(anyhow!("Unsupported geometry format: {}", extension)),
     };
 
     println!("Loaded and joined geometry for {} areas", small_areas.len());
 
+    // 3. Check the join
+    let matched_ids: HashSet<&str> = small_areas.iter().map(|a| a.id.as_str()).collect();
+    report.matched = matched_ids.len();
+    let mut unmatched_csv: Vec<&String> = csv_data.keys()
+        .filter(|id| !matched_ids.contains(id.as_str()) && !report.non_polygon_ids.contains(*id))
+        .collect();
+    unmatched_csv.sort();
+    for id in unmatched_csv {
+        report.unmatched_csv_rows.push(id);
+    }
+
+    if report.matched == 0 {
+        return Err(anyhow!(
+            "No boundary features joined to the CSV for level '{}'. Check join_column_shape ('{}') and join_column_csv ('{}')",
+            level.name, level.join_column_shape, level.join_column_csv
+        ));
+    }
+
+    report.apply_policy(config.input.join_policy)?;
+
     Ok(small_areas)
 }
 
 
-fn load_csv_data(config: &AppConfig) -> Result<HashMap<String, HashMap<String, HashMap<String, u32>>>> {
-    let file = File::open(&config.input.data_csv)
-        .with_context(|| format!("Failed to open CSV file: {:?}", config.input.data_csv))?;
+/// A CSV file and the datasets read from it. Datasets without their own
+/// `data_csv` share the one of their geography level.
+#[derive(Debug, Clone)]
+pub struct CsvSource {
+    pub level: String,
+    pub path: PathBuf,
+    pub join_column: String,
+    pub datasets: Vec<String>,
+    pub vintage: Vintage,
+    // Census year of a `Vintage::Previous` source
+    pub previous_year: Option<String>,
+}
+
+/// Groups the configured datasets by the level and CSV (and join column) they read from.
+pub fn csv_sources(config: &AppConfig) -> Vec<CsvSource> {
+    let levels = config.geography_levels();
+    let mut dataset_names: Vec<&String> = config.processing.datasets.keys().collect();
+    dataset_names.sort();
+
+    let mut sources: Vec<CsvSource> = Vec::new();
+    for dataset_name in dataset_names {
+        let dataset_config = &config.processing.datasets[dataset_name];
+        let Some(level) = levels.iter().find(|l| l.name == dataset_config.level()) else {
+            continue; // Unknown levels are rejected when the config loads
+        };
+        let path = dataset_config.data_csv.as_ref().unwrap_or(&level.data_csv);
+        let join_column = dataset_config.join_column_csv.as_ref().unwrap_or(&level.join_column_csv);
+
+        match sources.iter_mut().find(|s| s.level == level.name && &s.path == path && &s.join_column == join_column) {
+            Some(source) => source.datasets.push(dataset_name.clone()),
+            None => sources.push(CsvSource {
+                level: level.name.clone(),
+                path: path.clone(),
+                join_column: join_column.clone(),
+                datasets: vec![dataset_name.clone()],
+                vintage: Vintage::Current,
+                previous_year: None,
+            }),
+        }
+    }
+    sources
+}
+
+/// CSV sources of each earlier census every dataset is compared with.
+pub fn comparison_sources(config: &AppConfig) -> Vec<CsvSource> {
+    let levels = config.geography_levels();
+    let mut dataset_names: Vec<&String> = config.processing.datasets.keys().collect();
+    dataset_names.sort();
+
+    let mut sources = Vec::new();
+    for dataset_name in dataset_names {
+        let dataset_config = &config.processing.datasets[dataset_name];
+        let Some(level) = levels.iter().find(|l| l.name == dataset_config.level()) else {
+            continue;
+        };
+        for compare in &dataset_config.compare {
+            sources.push(CsvSource {
+                level: level.name.clone(),
+                path: compare.data_csv.clone(),
+                join_column: compare.join_column_csv.clone().unwrap_or_else(|| level.join_column_csv.clone()),
+                datasets: vec![dataset_name.clone()],
+                vintage: Vintage::Previous,
+                previous_year: Some(compare.previous_year.clone()),
+            });
+        }
+    }
+    sources
+}
+
+/// Lists every column the source's datasets need that is not present in its CSV header,
+/// formatted as "Dataset / Category: COLUMN".
+pub fn missing_columns(config: &AppConfig, source: &CsvSource, headers: &csv::StringRecord) -> Vec<String> {
+    let present: HashSet<&str> = headers.iter().collect();
+    let mut missing = Vec::new();
+
+    for dataset_name in &source.datasets {
+        let dataset_config = &config.processing.datasets[dataset_name];
+        for category in &dataset_config.categories {
+            for col_name in &category.source_columns(source.vintage) {
+                if !present.contains(col_name.as_str()) {
+                    missing.push(format!("{} / {}: {}", dataset_name, category.name, col_name));
+                }
+            }
+        }
+        if let Some(ns_config) = &dataset_config.not_stated {
+            let column = ns_config.column_for(source.vintage);
+            if !present.contains(column) {
+                missing.push(format!("{} / Not Stated: {}", dataset_name, column));
+            }
+        }
+    }
+
+    missing
+}
+
+/// Reads every CSV source of a level and merges them by area id.
+fn load_csv_data(config: &AppConfig, sources: &[&CsvSource], report: &mut JoinReport) -> Result<CsvData> {
+    let mut data_map: CsvData = HashMap::new();
+
+    for source in sources {
+        let source_data = load_csv_source(config, source, report)?;
+        if sources.len() > 1 {
+            println!("Loaded {} areas from {:?} ({})", source_data.len(), source.path, source.datasets.join(", "));
+        }
+        for (id, area_datasets) in source_data {
+            let merged = data_map.entry(id.clone()).or_default();
+            for (dataset, counts) in area_datasets {
+                // The same dataset from two sources must agree; the later source wins otherwise
+                if merged.get(&dataset).is_some_and(|existing| *existing != counts) {
+                    report.conflicting_csv_ids.push(&id);
+                }
+                merged.insert(dataset, counts);
+            }
+        }
+    }
+
+    // With several sources, an area missing from any of them would silently lose those datasets
+    if sources.len() > 1 {
+        let mut partial: Vec<&String> = data_map.iter()
+            .filter(|(_, area_datasets)| sources.iter().any(|s| !area_datasets.contains_key(&s.datasets[0])))
+            .map(|(id, _)| id)
+            .collect();
+        partial.sort();
+        for id in partial {
+            report.partial_csv_ids.push(id);
+        }
+    }
+
+    Ok(data_map)
+}
+
+fn load_csv_source(config: &AppConfig, source: &CsvSource, report: &mut JoinReport) -> Result<CsvData> {
+    let file = File::open(&source.path)
+        .with_context(|| format!("Failed to open CSV file: {:?}", source.path))?;
     let mut rdr = ReaderBuilder::new().from_reader(file);
     let headers = rdr.headers()?.clone();
 
     // Identify indices for join column and all category columns
-    let join_col_idx = headers.iter().position(|h| h == config.input.join_column_csv)
-        .ok_or_else(|| anyhow!("Join column '{}' not found in CSV", config.input.join_column_csv))?;
+    let join_col_idx = headers.iter().position(|h| h == source.join_column)
+        .ok_or_else(|| anyhow!("Join column '{}' not found in CSV {:?}", source.join_column, source.path))?;
+
+    // Every configured column must exist before we read a single row
+    let missing = missing_columns(config, source, &headers);
+    if !missing.is_empty() {
+        return Err(anyhow!(
+            "CSV {:?} is missing {} column(s) required by the config:\n  {}",
+            source.path, missing.len(), missing.join("\n  ")
+        ));
+    }
 
     // Map column names to indices for faster lookup
     let col_indices: HashMap<String, usize> = headers.iter().enumerate()
         .map(|(i, h)| (h.to_string(), i))
         .collect();
 
+    // Parse derived-category expressions once per source, keyed by (dataset, category)
+    let mut expressions: HashMap<(&str, &str), Expr> = HashMap::new();
+    for dataset_name in &source.datasets {
+        for category in &config.processing.datasets[dataset_name].categories {
+            if let Some(expr) = category.parsed_expression(source.vintage)? {
+                expressions.insert((dataset_name, &category.name), expr);
+            }
+        }
+    }
+
+    let markers = &config.input.suppression_markers;
+    let mut bad_cells = IdSample::default();
+    let mut suppressed_cells = 0;
+    let mut clamped_values = 0;
+    let mut too_large = IdSample::default();
+
+    // Strict parse: suppression markers read as 0, anything else non-numeric is recorded as an error.
+    // Each cell is parsed once per row, however many datasets or categories read it.
+    let mut row_cells: (u64, Vec<Option<u32>>) = (0, vec![None; headers.len()]);
+    let mut read_count = |record: &csv::StringRecord, col_name: &str| -> u32 {
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        if row_cells.0 != line {
+            row_cells = (line, vec![None; headers.len()]);
+        }
+        let index = col_indices[col_name];
+        if let Some(count) = row_cells.1[index] {
+            return count;
+        }
+        let value = record.get(index).unwrap_or("").trim();
+        let count = if markers.iter().any(|m| m == value) {
+            suppressed_cells += 1;
+            0
+        } else {
+            value.parse().unwrap_or_else(|_| {
+                bad_cells.push(&format!("line {}, column {}: {:?}", line, col_name, value));
+                0
+            })
+        };
+        row_cells.1[index] = Some(count);
+        count
+    };
+
     let mut data_map = HashMap::new();
 
     for result in rdr.records() {
         let record = result?;
-        let id = record.get(join_col_idx).unwrap_or("").to_string();
-        
-        if id.is_empty() { continue; }
+        let id = record.get(join_col_idx).unwrap_or("").trim().to_string();
+        report.csv_rows += 1;
+
+        if id.is_empty() {
+            report.null_csv_ids += 1;
+            continue;
+        }
 
         let mut area_datasets = HashMap::new();
 
-        for (dataset_name, dataset_config) in &config.processing.datasets {
+        for dataset_name in &source.datasets {
+            let dataset_config = &config.processing.datasets[dataset_name];
             let mut population_data = HashMap::new();
 
             // 1. Process Categories
             for category in &dataset_config.categories {
-                let mut total = 0;
-                for col_name in &category.columns {
-                     if let Some(&idx) = col_indices.get(col_name) {
-                         let val: u32 = record.get(idx).unwrap_or("0").parse().unwrap_or(0);
-                         total += val;
-                     }
-                }
+                let total: u64 = match expressions.get(&(dataset_name.as_str(), category.name.as_str())) {
+                    Some(expr) => {
+                        let value = expr.eval(&mut |col| read_count(&record, col) as f64);
+                        // Negative or undefined results (e.g. division by zero) can't be drawn as dots
+                        if value.is_finite() && value >= 0.0 {
+                            value.round() as u64
+                        } else {
+                            clamped_values += 1;
+                            0
+                        }
+                    }
+                    None => category.columns_for(source.vintage).iter().map(|col| read_count(&record, col) as u64).sum(),
+                };
+                let total = u32::try_from(total).unwrap_or_else(|_| {
+                    too_large.push(&format!("{} ({}, {}): {}", id, dataset_name, category.name, total));
+                    0
+                });
                 population_data.insert(category.name.clone(), total);
             }
 
             // 2. Process Not Stated
             if let Some(ns_config) = &dataset_config.not_stated {
-                if let Some(&idx) = col_indices.get(&ns_config.column) {
-                    let val: u32 = record.get(idx).unwrap_or("0").parse().unwrap_or(0);
-                    population_data.insert("Not Stated".to_string(), val);
-                }
+                let val = read_count(&record, ns_config.column_for(source.vintage));
+                population_data.insert("Not Stated".to_string(), val);
             }
             
             area_datasets.insert(dataset_name.clone(), population_data);
         }
 
+        match data_map.get(&id) {
+            // Same id twice with identical numbers is a harmless duplicate; different numbers is a conflict
+            Some(existing) if *existing != area_datasets => report.conflicting_csv_ids.push(&id),
+            Some(_) => report.duplicate_csv_ids.push(&id),
+            None => {}
+        }
         data_map.insert(id, area_datasets);
     }
 
+    if bad_cells.count > 0 {
+        return Err(anyhow!(
+            "CSV {:?} has {} non-numeric cell(s) in census columns:\n  {}\nAdd CSO suppression markers to input.suppression_markers to read them as 0",
+            source.path, bad_cells.count, bad_cells.sample.join("\n  ")
+        ));
+    }
+    if too_large.count > 0 {
+        return Err(anyhow!(
+            "CSV {:?} has {} category total(s) too large to count:\n  {}",
+            source.path, too_large.count, too_large.sample.join("\n  ")
+        ));
+    }
+    if suppressed_cells > 0 {
+        println!("Read {} suppressed cell(s) as 0 from {:?}", suppressed_cells, source.path);
+    }
+    if clamped_values > 0 {
+        println!("WARNING: {} category expression result(s) were negative or undefined and set to 0", clamped_values);
+    }
+
     Ok(data_map)
 }
 
 
+/// Loads each earlier census of the compared datasets, maps it onto the current
+/// boundaries and stores it in `SmallArea::previous_data`.
+fn attach_comparisons(config: &AppConfig, small_areas: &mut [SmallArea]) -> Result<()> {
+    for source in comparison_sources(config) {
+        let dataset_name = &source.datasets[0];
+        let dataset_config = &config.processing.datasets[dataset_name];
+        let Some(compare) = dataset_config.compare.iter().find(|c| Some(&c.previous_year) == source.previous_year.as_ref()) else {
+            continue;
+        };
+
+        let mut report = JoinReport {
+            level: format!("{} {}", dataset_name, compare.previous_year),
+            ..Default::default()
+        };
+        let previous: HashMap<String, HashMap<String, u32>> = load_csv_source(config, &source, &mut report)?
+            .into_iter()
+            .filter_map(|(id, mut datasets)| datasets.remove(dataset_name).map(|counts| (id, counts)))
+            .collect();
+
+        let mapping = compare.mapping.as_ref().map(load_boundary_mapping).transpose()?;
+        let (mapped, unmapped) = map_to_current_boundaries(&previous, mapping.as_deref());
+
+        let mut attached = HashSet::new();
+        for area in small_areas.iter_mut().filter(|a| a.level == dataset_config.level()) {
+            report.features += 1;
+            match mapped.get(&area.id) {
+                Some(counts) => {
+                    area.previous_data.entry(dataset_name.clone()).or_default()
+                        .insert(compare.previous_year.clone(), counts.clone());
+                    attached.insert(area.id.clone());
+                }
+                None => report.unmatched_features.push(&area.id),
+            }
+        }
+        report.matched = attached.len();
+        let mut unmatched: Vec<&String> = mapped.keys().filter(|id| !attached.contains(*id)).collect();
+        unmatched.sort();
+        for id in unmatched {
+            report.unmatched_csv_rows.push(id);
+        }
+
+        println!(
+            "Comparison {} {} -> {}: {} earlier areas, {} current areas matched",
+            dataset_name, compare.previous_year, compare.year, previous.len(), report.matched
+        );
+        if report.matched == 0 {
+            return Err(anyhow!(
+                "No current areas matched the {} data for dataset '{}'. Check join_column_csv ('{}') and the boundary mapping",
+                compare.previous_year, dataset_name, source.join_column
+            ));
+        }
+        if unmapped.count > 0 && config.input.join_policy != JoinPolicy::Ignore {
+            println!(
+                "  WARNING: {} earlier areas are missing from the boundary mapping (e.g. {})",
+                unmapped.count, unmapped.sample.join(", ")
+            );
+        }
+        report.apply_policy(config.input.join_policy)?;
+        if unmapped.count > 0 && config.input.join_policy == JoinPolicy::Error {
+            return Err(anyhow!("Earlier areas missing from the boundary mapping and join_policy = \"error\""));
+        }
+    }
+    Ok(())
+}
+
+/// Reads a boundary correspondence table as (earlier id, current id, weight) rows.
+fn load_boundary_mapping(mapping: &BoundaryMappingConfig) -> Result<Vec<(String, String, f64)>> {
+    let file = File::open(&mapping.csv)
+        .with_context(|| format!("Failed to open boundary mapping: {:?}", mapping.csv))?;
+    let mut rdr = ReaderBuilder::new().from_reader(file);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|h| h == name)
+        .ok_or_else(|| anyhow!("Column '{}' not found in boundary mapping {:?}", name, mapping.csv));
+
+    let from_idx = column(&mapping.from_column)?;
+    let to_idx = column(&mapping.to_column)?;
+    let weight_idx = mapping.weight_column.as_deref().map(column).transpose()?;
+
+    let mut rows = Vec::new();
+    for result in rdr.records() {
+        let record = result?;
+        let from = record.get(from_idx).unwrap_or("").trim().to_string();
+        let to = record.get(to_idx).unwrap_or("").trim().to_string();
+        let weight = match weight_idx {
+            Some(idx) => {
+                let value = record.get(idx).unwrap_or("").trim();
+                value.parse::<f64>().map_err(|_| anyhow!(
+                    "Invalid weight {:?} on line {} of {:?}",
+                    value, record.position().map(|p| p.line()).unwrap_or(0), mapping.csv
+                ))?
+            }
+            None => 1.0,
+        };
+        rows.push((from, to, weight));
+    }
+
+    // Without a weight column, each earlier area is split evenly between its successors
+    if weight_idx.is_none() {
+        let mut targets: HashMap<String, usize> = HashMap::new();
+        for (from, _, _) in &rows {
+            *targets.entry(from.clone()).or_default() += 1;
+        }
+        for (from, _, weight) in rows.iter_mut() {
+            *weight = 1.0 / targets[from.as_str()] as f64;
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Re-expresses earlier-census counts on the current boundaries. Returns the
+/// mapped counts and the earlier ids the mapping didn't cover.
+fn map_to_current_boundaries(
+    previous: &HashMap<String, HashMap<String, u32>>,
+    mapping: Option<&[(String, String, f64)]>,
+) -> (HashMap<String, HashMap<String, u32>>, IdSample) {
+    let mut unmapped = IdSample::default();
+    let Some(mapping) = mapping else {
+        // Boundaries unchanged: ids carry straight across
+        return (previous.clone(), unmapped);
+    };
+
+    let mut totals: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    for (from, to, weight) in mapping {
+        if let Some(counts) = previous.get(from) {
+            let target = totals.entry(to.clone()).or_default();
+            for (category, count) in counts {
+                *target.entry(category.clone()).or_default() += *count as f64 * weight;
+            }
+        }
+    }
+
+    let mapped_from: HashSet<&str> = mapping.iter().map(|(from, _, _)| from.as_str()).collect();
+    let mut missing: Vec<&String> = previous.keys().filter(|id| !mapped_from.contains(id.as_str())).collect();
+    missing.sort();
+    for id in missing {
+        unmapped.push(id);
+    }
+
+    let mapped = totals.into_iter()
+        .map(|(id, counts)| (id, counts.into_iter().map(|(c, v)| (c, v.round() as u32)).collect()))
+        .collect();
+    (mapped, unmapped)
+}
+
 fn load_shapefile_and_join(
-    config: &AppConfig,
-    csv_data: &HashMap<String, HashMap<String, HashMap<String, u32>>>
+    level: &GeographyLevel,
+    csv_data: &CsvData,
+    report: &mut JoinReport,
 ) -> Result<Vec<SmallArea>> {
-    let mut reader = Reader::from_path(&config.input.shapefile)
-        .with_context(|| format!("Failed to open Shapefile: {:?}", config.input.shapefile))?;
+    let mut reader = Reader::from_path(&level.shapefile)
+        .with_context(|| format!("Failed to open Shapefile: {:?}", level.shapefile))?;
+
+    let crs = Crs::resolve(level.crs.as_deref(), &level.shapefile)?;
+    if !crs.is_wgs84() {
+        println!("Reprojecting boundaries from {:?} to WGS84", crs);
+    }
 
     let mut small_areas = Vec::new();
+    let mut seen_ids = HashSet::new();
 
     for result in reader.iter_shapes_and_records() {
         let (shape, record) = result?;
+        report.features += 1;
         
         // Find the Join ID in the shapefile record (dbase)
-        let id_value = record.get(&config.input.join_column_shape)
-            .ok_or_else(|| anyhow!("Join column '{}' not found in Shapefile", config.input.join_column_shape))?;
+        let id_value = record.get(&level.join_column_shape)
+            .ok_or_else(|| anyhow!("Join column '{}' not found in Shapefile", level.join_column_shape))?;
         
         let id = match id_value {
-            shapefile::dbase::FieldValue::Character(Some(s)) => s.clone(),
-            shapefile::dbase::FieldValue::Character(None) => continue, // Skip if null
+            shapefile::dbase::FieldValue::Character(Some(s)) if !s.trim().is_empty() => s.trim().to_string(),
+            shapefile::dbase::FieldValue::Character(_) => {
+                report.null_feature_ids += 1;
+                continue;
+            }
             _ => return Err(anyhow!("Shapefile join column must be a string")),
         };
 
+        if !seen_ids.insert(id.clone()) {
+            report.duplicate_feature_ids.push(&id);
+        }
+
         // If we have matching CSV data, create the SmallArea object
         if let Some(pop_data) = csv_data.get(&id) {
-             let geometry = match shape {
-                shapefile::Shape::Polygon(polygon) => {
-                    let geo_polygon: MultiPolygon<f64> = polygon.try_into()
-                        .map_err(|e| anyhow!("Failed to convert polygon: {:?}", e))?;
-                    geo_polygon
-                },
-                 shapefile::Shape::PolygonM(polygon) => {
-                     let geo_polygon: MultiPolygon<f64> = polygon.try_into()
-                         .map_err(|e| anyhow!("Failed to convert polygonM: {:?}", e))?;
-                     geo_polygon
-                 },
-                 shapefile::Shape::PolygonZ(polygon) => {
-                     let geo_polygon: MultiPolygon<f64> = polygon.try_into()
-                         .map_err(|e| anyhow!("Failed to convert polygonZ: {:?}", e))?;
-                     geo_polygon
-                 },
-                _ => continue, // Skip non-polygon shapes
+             let mut geometry: MultiPolygon<f64> = match shape {
+                shapefile::Shape::Polygon(polygon) => polygon.into(),
+                 shapefile::Shape::PolygonM(polygon) => polygon.into(),
+                 shapefile::Shape::PolygonZ(polygon) => polygon.into(),
+                _ => {
+                    report.push_non_polygon(&id);
+                    continue;
+                }
             };
+            crs.reproject(&mut geometry);
+            let name = level.name_column.as_ref()
+                .and_then(|column| record.get(column))
+                .and_then(field_text);
+            let attributes = level.attributes.iter()
+                .filter_map(|column| Some((column.clone(), field_text(record.get(column)?)?)))
+                .collect();
 
             small_areas.push(SmallArea {
                 id: id.clone(),
+                level: level.name.clone(),
+                name,
+                attributes,
                 geometry,
                 population_data: pop_data.clone(),
+                previous_data: HashMap::new(),
+                masked: HashMap::new(),
             });
+        } else {
+            report.unmatched_features.push(&id);
         }
     }
 
@@ -146,16 +701,17 @@ fn load_shapefile_and_join(
 }
 
 fn load_geojson_and_join(
-    config: &AppConfig,
-    csv_data: &HashMap<String, HashMap<String, HashMap<String, u32>>>
+    level: &GeographyLevel,
+    csv_data: &CsvData,
+    report: &mut JoinReport,
 ) -> Result<Vec<SmallArea>> {
     use std::io::BufReader;
-    use geojson::{GeoJson, Value};
+    use geojson::GeoJson;
     use std::convert::TryInto; // For TryInto<MultiPolygon>
 
-    println!("Loading GeoJSON from {:?}...", config.input.shapefile);
-    let file = File::open(&config.input.shapefile)
-        .with_context(|| format!("Failed to open GeoJSON file: {:?}", config.input.shapefile))?;
+    println!("Loading GeoJSON from {:?}...", level.shapefile);
+    let file = File::open(&level.shapefile)
+        .with_context(|| format!("Failed to open GeoJSON file: {:?}", level.shapefile))?;
     let reader = BufReader::new(file);
     
     // Parse the GeoJSON. warning: this loads the whole file into memory.
@@ -166,24 +722,47 @@ fn load_geojson_and_join(
         _ => return Err(anyhow!("GeoJSON must be a FeatureCollection")),
     };
 
+    // Explicit config wins, then the legacy GeoJSON "crs" member, then a .prj alongside the file
+    let crs = match &level.crs {
+        Some(name) => Crs::from_name(name)?,
+        None => match Crs::from_geojson_members(collection.foreign_members.as_ref())? {
+            Some(crs) => crs,
+            None => Crs::resolve(None, &level.shapefile)?,
+        },
+    };
+    if !crs.is_wgs84() {
+        println!("Reprojecting boundaries from {:?} to WGS84", crs);
+    }
+
     let mut small_areas = Vec::new();
+    let mut seen_ids = HashSet::new();
 
     for feature in collection.features {
+        report.features += 1;
+
         // 1. Get ID
         let id_val = feature.properties.as_ref()
-            .and_then(|props| props.get(&config.input.join_column_shape));
+            .and_then(|props| props.get(&level.join_column_shape));
         
         let id = match id_val {
-            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::String(s)) if !s.trim().is_empty() => s.trim().to_string(),
             Some(serde_json::Value::Number(n)) => n.to_string(),
-            _ => continue, // Skip if no ID or not string/number
+            _ => {
+                // No ID or not string/number
+                report.null_feature_ids += 1;
+                continue;
+            }
         };
 
+        if !seen_ids.insert(id.clone()) {
+            report.duplicate_feature_ids.push(&id);
+        }
+
         // 2. Check match
         if let Some(pop_data) = csv_data.get(&id) {
              // 3. Get Geometry
              // geojson crate Value -> geo types -> MultiPolygon
-             let geometry = match feature.geometry {
+             let mut geometry = match feature.geometry {
                  Some(geo) => {
                      let valid_geo: geo::Geometry<f64> = geo.value.try_into()
                         .map_err(|e| anyhow!("Failed to convert geojson geometry: {:?}", e))?;
@@ -191,20 +770,60 @@ fn load_geojson_and_join(
                      match valid_geo {
                          geo::Geometry::MultiPolygon(mp) => mp,
                          geo::Geometry::Polygon(p) => MultiPolygon::new(vec![p]),
-                         _ => continue, // Skip points/lines
+                         _ => {
+                             report.push_non_polygon(&id);
+                             continue;
+                         }
                      }
                  },
-                 None => continue,
+                 None => {
+                     report.push_non_polygon(&id);
+                     continue;
+                 }
              };
+             crs.reproject(&mut geometry);
+             let property = |column: &String| feature.properties.as_ref()?.get(column).and_then(json_text);
+             let name = level.name_column.as_ref().and_then(property);
+             let attributes = level.attributes.iter()
+                 .filter_map(|column| Some((column.clone(), property(column)?)))
+                 .collect();
 
             small_areas.push(SmallArea {
                 id,
+                level: level.name.clone(),
+                name,
+                attributes,
                 geometry,
                 population_data: pop_data.clone(),
+                previous_data: HashMap::new(),
+                masked: HashMap::new(),
             });
+        } else {
+            report.unmatched_features.push(&id);
         }
     }
 
     Ok(small_areas)
 }
 
+/// A dbase attribute as display text; `None` when empty or not a text-like field.
+fn field_text(value: &shapefile::dbase::FieldValue) -> Option<String> {
+    use shapefile::dbase::FieldValue;
+    let text = match value {
+        FieldValue::Character(Some(s)) | FieldValue::Memo(s) => s.trim().to_string(),
+        FieldValue::Numeric(Some(n)) => n.to_string(),
+        FieldValue::Integer(n) => n.to_string(),
+        _ => return None,
+    };
+    Some(text).filter(|t| !t.is_empty())
+}
+
+/// A GeoJSON property as display text; `None` when empty, null or not a string or number.
+fn json_text(value: &serde_json::Value) -> Option<String> {
+    let text = match value {
+        serde_json::Value::String(s) => s.trim().to_string(),
+        serde_json::Value::Number(n) => n.to_string(),
+        _ => return None,
+    };
+    Some(text).filter(|t| !t.is_empty())
+}
@@ -0,0 +1,226 @@
+use anyhow::{Result, anyhow};
+
+/// Arithmetic over CSV columns for derived categories, e.g. `T2_2ALL - T2_2WI` or `0.5 * T2_2OTH`.
+///
+/// Supports numbers, column names, `+ - * /`, unary minus and parentheses.
+/// Column names that aren't plain identifiers can be written in double quotes.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(f64),
+    Column(String),
+    Neg(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+}
+
+impl Expr {
+    pub fn parse(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let expr = parser.expression()?;
+        if parser.pos != tokens.len() {
+            return Err(anyhow!("unexpected {:?} in expression '{}'", tokens[parser.pos], input));
+        }
+        Ok(expr)
+    }
+
+    /// Every column the expression reads, in order of appearance.
+    pub fn columns(&self) -> Vec<&str> {
+        let mut cols = Vec::new();
+        self.collect_columns(&mut cols);
+        cols
+    }
+
+    fn collect_columns<'a>(&'a self, cols: &mut Vec<&'a str>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Column(name) => cols.push(name),
+            Expr::Neg(inner) => inner.collect_columns(cols),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_columns(cols);
+                rhs.collect_columns(cols);
+            }
+        }
+    }
+
+    pub fn eval(&self, lookup: &mut impl FnMut(&str) -> f64) -> f64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Column(name) => lookup(name),
+            Expr::Neg(inner) => -inner.eval(lookup),
+            Expr::Binary(op, lhs, rhs) => {
+                let (l, r) = (lhs.eval(lookup), rhs.eval(lookup));
+                match op {
+                    Op::Add => l + r,
+                    Op::Sub => l - r,
+                    Op::Mul => l * r,
+                    Op::Div => l / r,
+                }
+            }
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let n = text.parse().map_err(|_| anyhow!("invalid number '{}' in expression", text))?;
+            tokens.push(Token::Number(n));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let end = chars[i + 1..].iter().position(|&ch| ch == '"')
+                .ok_or_else(|| anyhow!("unterminated quoted column in expression '{}'", input))?;
+            tokens.push(Token::Ident(chars[i + 1..i + 1 + end].iter().collect()));
+            i += end + 2;
+        } else if "+-*/".contains(c) {
+            tokens.push(Token::Op(c));
+            i += 1;
+        } else if c == '(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == ')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else {
+            return Err(anyhow!("unexpected character '{}' in expression '{}'", c, input));
+        }
+    }
+
+    if tokens.is_empty() {
+        return Err(anyhow!("empty expression"));
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<Expr> {
+        let mut lhs = self.term()?;
+        while let Some(Token::Op(c @ ('+' | '-'))) = self.peek() {
+            let op = if *c == '+' { Op::Add } else { Op::Sub };
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    // term := factor (('*' | '/') factor)*
+    fn term(&mut self) -> Result<Expr> {
+        let mut lhs = self.factor()?;
+        while let Some(Token::Op(c @ ('*' | '/'))) = self.peek() {
+            let op = if *c == '*' { Op::Mul } else { Op::Div };
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.factor()?));
+        }
+        Ok(lhs)
+    }
+
+    // factor := number | column | '-' factor | '(' expression ')'
+    fn factor(&mut self) -> Result<Expr> {
+        match self.next().cloned() {
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => Ok(Expr::Column(name)),
+            Some(Token::Op('-')) => Ok(Expr::Neg(Box::new(self.factor()?))),
+            Some(Token::LParen) => {
+                let inner = self.expression()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(inner),
+                    _ => Err(anyhow!("missing ')' in expression")),
+                }
+            }
+            Some(token) => Err(anyhow!("unexpected {:?} in expression", token)),
+            None => Err(anyhow!("expression ends unexpectedly")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn eval(input: &str, columns: &[(&str, f64)]) -> f64 {
+        let columns: HashMap<&str, f64> = columns.iter().copied().collect();
+        Expr::parse(input).unwrap().eval(&mut |name| columns.get(name).copied().unwrap_or(f64::NAN))
+    }
+
+    #[test]
+    fn precedence_and_associativity() {
+        assert_eq!(eval("2 + 3 * 4", &[]), 14.0);
+        assert_eq!(eval("(2 + 3) * 4", &[]), 20.0);
+        assert_eq!(eval("10 - 4 - 3", &[]), 3.0);
+        assert_eq!(eval("24 / 4 / 2", &[]), 3.0);
+        assert_eq!(eval("-2 * -3 + 1", &[]), 7.0);
+        assert_eq!(eval("ALL - 0.5 * \"Other White\"", &[("ALL", 100.0), ("Other White", 30.0)]), 85.0);
+    }
+
+    #[test]
+    fn division_by_zero_is_not_finite() {
+        // The CSV loader counts these and stores 0
+        assert!(!eval("A / B", &[("A", 5.0), ("B", 0.0)]).is_finite());
+        assert!(!eval("A / B", &[("A", 0.0), ("B", 0.0)]).is_finite());
+    }
+
+    #[test]
+    fn columns_lists_every_reference() {
+        // The CSV loader checks these against the header, so unknown columns fail up front
+        let expr = Expr::parse("T2_2ALL - (T2_2WI + \"T2 2OTH\") / T2_2ALL").unwrap();
+        assert_eq!(expr.columns(), vec!["T2_2ALL", "T2_2WI", "T2 2OTH", "T2_2ALL"]);
+        assert!(eval("MISSING + 1", &[]).is_nan());
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for input in ["", "A +", "(A + B", "A B", "A + ) B", "\"A", "A % B", "1.2.3"] {
+            assert!(Expr::parse(input).is_err(), "{:?} parsed", input);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use crate::types::SmallArea;
+use geo::algorithm::line_intersection::{line_intersection, LineIntersection};
+use geo::{Area, BooleanOps, Coord, EuclideanLength, Line, LineString, MultiPolygon, Polygon, RemoveRepeatedPoints};
+use rayon::prelude::*;
+use rstar::{RTree, RTreeObject, AABB};
+use std::cell::Cell;
+use std::collections::HashSet;
+
+// Rings whose area is below this fraction of their squared perimeter are treated as
+// zero-area (collinear up to float noise); a square scores 1/16
+const MIN_RING_THINNESS: f64 = 1e-9;
+
+/// What the validation stage did to one area's geometry.
+#[derive(Debug, Clone)]
+pub struct GeometryRepair {
+    pub id: String,
+    pub level: String,
+    pub outcome: RepairOutcome,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepairOutcome {
+    Fixed,
+    // Nothing valid was left; the area is removed
+    Dropped,
+    // Still self-intersecting after the repair attempt; kept as loaded
+    Invalid,
+}
+
+impl RepairOutcome {
+    pub fn label(&self) -> &'static str {
+        match self {
+            RepairOutcome::Fixed => "fixed",
+            RepairOutcome::Dropped => "dropped",
+            RepairOutcome::Invalid => "invalid",
+        }
+    }
+}
+
+/// Validates every area's geometry, repairing what it can: non-finite coordinates, repeated
+/// vertices, zero-area rings and self-intersections. Areas with nothing valid left are
+/// removed. Returns what was changed, for logging and the generation report.
+pub fn repair_areas(areas: &mut Vec<SmallArea>) -> Vec<GeometryRepair> {
+    println!("Validating geometry of {} areas...", areas.len());
+
+    let repaired: Vec<(usize, GeometryRepair)> = areas.par_iter_mut()
+        .enumerate()
+        .filter_map(|(index, area)| {
+            let (geometry, outcome, fixes) = repair(&area.geometry)?;
+            area.geometry = geometry;
+            Some((index, GeometryRepair {
+                id: area.id.clone(),
+                level: area.level.clone(),
+                outcome,
+                detail: fixes.join(", "),
+            }))
+        })
+        .collect();
+
+    // By index, so a dropped area doesn't take a valid one sharing its id with it
+    let dropped: HashSet<usize> = repaired.iter()
+        .filter(|(_, r)| r.outcome == RepairOutcome::Dropped)
+        .map(|(index, _)| *index)
+        .collect();
+    let mut index = 0;
+    areas.retain(|_| {
+        index += 1;
+        !dropped.contains(&(index - 1))
+    });
+
+    let mut repairs: Vec<GeometryRepair> = repaired.into_iter().map(|(_, r)| r).collect();
+    repairs.sort_by(|a, b| (&a.level, &a.id).cmp(&(&b.level, &b.id)));
+
+    for outcome in [RepairOutcome::Fixed, RepairOutcome::Dropped, RepairOutcome::Invalid] {
+        let matching: Vec<&GeometryRepair> = repairs.iter().filter(|r| r.outcome == outcome).collect();
+        if matching.is_empty() {
+            continue;
+        }
+        println!("  Geometry {} for {} areas:", outcome.label(), matching.len());
+        for repair in matching.iter().take(10) {
+            println!("    {} ({}): {}", repair.id, repair.level, repair.detail);
+        }
+        if matching.len() > 10 {
+            println!("    ... and {} more (see the generation report)", matching.len() - 10);
+        }
+    }
+    repairs
+}
+
+/// Returns the repaired geometry, or `None` if it was valid already.
+fn repair(geometry: &MultiPolygon<f64>) -> Option<(MultiPolygon<f64>, RepairOutcome, Vec<String>)> {
+    let mut fixes = Vec::new();
+    let mut changed = false;
+
+    // Non-finite coordinates can't be drawn or tested; drop the rings holding them
+    let finite = |ring: &LineString<f64>| ring.0.iter().all(|c| c.x.is_finite() && c.y.is_finite());
+    let mut polygons: Vec<Polygon<f64>> = Vec::new();
+    let mut non_finite = 0;
+    for polygon in &geometry.0 {
+        if !finite(polygon.exterior()) {
+            non_finite += 1;
+            continue;
+        }
+        let interiors: Vec<LineString<f64>> = polygon.interiors().iter().filter(|r| finite(r)).cloned().collect();
+        non_finite += polygon.interiors().len() - interiors.len();
+        polygons.push(Polygon::new(polygon.exterior().clone(), interiors));
+    }
+    if non_finite > 0 {
+        fixes.push(format!("removed {} rings with non-finite coordinates", non_finite));
+        changed = true;
+    }
+
+    let before: usize = polygons.iter().map(coord_count).sum();
+    let mut multi = MultiPolygon::new(polygons).remove_repeated_points();
+    let removed = before - multi.0.iter().map(coord_count).sum::<usize>();
+    if removed > 0 {
+        fixes.push(format!("removed {} repeated vertices", removed));
+        changed = true;
+    }
+
+    let degenerate = |ring: &LineString<f64>| {
+        if ring.0.len() < 4 {
+            return true;
+        }
+        // A bow-tie's halves cancel out to zero area too, but it is repaired below instead
+        let polygon = Polygon::new(ring.clone(), vec![]);
+        let perimeter = ring.euclidean_length();
+        polygon.unsigned_area() <= MIN_RING_THINNESS * perimeter * perimeter
+            && self_intersections(&MultiPolygon::new(vec![polygon])) == 0
+    };
+    let ring_count = |m: &MultiPolygon<f64>| m.0.iter().map(|p| 1 + p.interiors().len()).sum::<usize>();
+    let rings_before = ring_count(&multi);
+    multi.0 = multi.0.into_iter()
+        .filter(|polygon| !degenerate(polygon.exterior()))
+        .map(|polygon| {
+            let (exterior, interiors) = polygon.into_inner();
+            Polygon::new(exterior, interiors.into_iter().filter(|r| !degenerate(r)).collect())
+        })
+        .collect();
+    let dropped_rings = rings_before - ring_count(&multi);
+    if dropped_rings > 0 {
+        fixes.push(format!("removed {} zero-area rings", dropped_rings));
+        changed = true;
+    }
+
+    if multi.0.is_empty() {
+        fixes.push("no valid polygons left".to_string());
+        return Some((multi, RepairOutcome::Dropped, fixes));
+    }
+
+    let crossings = self_intersections(&multi);
+    if crossings == 0 {
+        return changed.then_some((multi, RepairOutcome::Fixed, fixes));
+    }
+
+    // buffer(0)-style repair: re-noding through a boolean union splits bow-ties and
+    // merges overlapping parts. Kept only if the result is itself clean.
+    let rebuilt = guarded(|| multi.union(&MultiPolygon::new(vec![])))
+        .filter(|r| is_plausible(r) && !r.0.is_empty() && self_intersections(r) == 0);
+    match rebuilt {
+        Some(rebuilt) => {
+            fixes.push(format!("resolved {} self-intersections", crossings));
+            Some((rebuilt, RepairOutcome::Fixed, fixes))
+        }
+        None => {
+            fixes.push(format!("{} self-intersections could not be resolved", crossings));
+            Some((multi, RepairOutcome::Invalid, fixes))
+        }
+    }
+}
+
+fn coord_count(polygon: &Polygon<f64>) -> usize {
+    polygon.exterior().0.len() + polygon.interiors().iter().map(|r| r.0.len()).sum::<usize>()
+}
+
+struct Segment {
+    line: Line<f64>,
+    ring: usize,
+    index: usize,
+    ring_segments: usize,
+}
+
+impl RTreeObject for Segment {
+    type Envelope = AABB<[f64; 2]>;
+
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_corners([self.line.start.x, self.line.start.y], [self.line.end.x, self.line.end.y])
+    }
+}
+
+/// Counts proper crossings and collinear overlaps between ring segments. Rings touching
+/// at a vertex are allowed, as in OGC simple features.
+pub fn self_intersections(geometry: &MultiPolygon<f64>) -> usize {
+    let mut segments = Vec::new();
+    let rings = geometry.0.iter().flat_map(|p| std::iter::once(p.exterior()).chain(p.interiors()));
+    for (ring, line_string) in rings.enumerate() {
+        let lines: Vec<Line<f64>> = line_string.lines().collect();
+        let ring_segments = lines.len();
+        segments.extend(lines.into_iter().enumerate().map(|(index, line)| Segment { line, ring, index, ring_segments }));
+    }
+
+    let adjacent = |a: &Segment, b: &Segment| {
+        a.ring == b.ring && {
+            let (i, j) = (a.index.min(b.index), a.index.max(b.index));
+            j - i == 1 || (i == 0 && j == a.ring_segments - 1)
+        }
+    };
+
+    let tree = RTree::bulk_load(segments);
+    let mut count = 0;
+    for a in tree.iter() {
+        for b in tree.locate_in_envelope_intersecting(&a.envelope()) {
+            // Each pair once, and never a segment with itself or its neighbours
+            if (b.ring, b.index) <= (a.ring, a.index) || adjacent(a, b) {
+                continue;
+            }
+            match line_intersection(a.line, b.line) {
+                Some(LineIntersection::SinglePoint { is_proper: true, .. }) => count += 1,
+                Some(LineIntersection::Collinear { intersection }) if intersection.start != intersection.end => count += 1,
+                _ => {}
+            }
+        }
+    }
+    count
+}
+
+thread_local! {
+    static GUARDED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Wraps the current panic hook so panics caught by `guarded` aren't printed; callers report
+/// those failures themselves. Call once at startup, before any other hook is installed.
+pub fn install_quiet_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if !GUARDED.with(Cell::get) {
+            default_hook(info);
+        }
+    }));
+}
+
+/// Runs a boolean op, turning a panic inside `geo` into `None`.
+pub fn guarded<T>(op: impl FnOnce() -> T) -> Option<T> {
+    let outer = GUARDED.with(|g| g.replace(true));
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(op)).ok();
+    GUARDED.with(|g| g.set(outer));
+    result
+}
+
+/// Cheap sanity check on a boolean op result: finite coordinates and closed rings of 4+ points.
+pub fn is_plausible(geometry: &MultiPolygon<f64>) -> bool {
+    geometry.0.iter()
+        .flat_map(|p| std::iter::once(p.exterior()).chain(p.interiors()))
+        .all(|ring| {
+            ring.0.len() >= 4
+                && ring.is_closed()
+                && ring.0.iter().all(|c: &Coord<f64>| c.x.is_finite() && c.y.is_finite())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn polygon(coords: &[(f64, f64)]) -> Polygon<f64> {
+        Polygon::new(LineString::from(coords.to_vec()), vec![])
+    }
+
+    fn multi(polygons: Vec<Polygon<f64>>) -> MultiPolygon<f64> {
+        MultiPolygon::new(polygons)
+    }
+
+    const SQUARE: [(f64, f64); 5] = [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 0.0)];
+    const BOW_TIE: [(f64, f64); 5] = [(0.0, 0.0), (2.0, 2.0), (2.0, 0.0), (0.0, 2.0), (0.0, 0.0)];
+
+    #[test]
+    fn counts_crossings_and_overlaps() {
+        assert_eq!(self_intersections(&multi(vec![polygon(&SQUARE)])), 0);
+        assert_eq!(self_intersections(&multi(vec![polygon(&BOW_TIE)])), 1);
+        // Touching at a vertex is allowed
+        let corner = polygon(&[(1.0, 1.0), (2.0, 1.0), (2.0, 2.0), (1.0, 2.0), (1.0, 1.0)]);
+        assert_eq!(self_intersections(&multi(vec![polygon(&SQUARE), corner])), 0);
+        // Sharing part of an edge is not
+        let beside = polygon(&[(1.0, 0.5), (2.0, 0.5), (2.0, 1.5), (1.0, 1.5), (1.0, 0.5)]);
+        assert_eq!(self_intersections(&multi(vec![polygon(&SQUARE), beside])), 1);
+    }
+
+    #[test]
+    fn valid_geometry_is_left_alone() {
+        assert!(repair(&multi(vec![polygon(&SQUARE)])).is_none());
+    }
+
+    #[test]
+    fn splits_bow_tie() {
+        let (fixed, outcome, fixes) = repair(&multi(vec![polygon(&BOW_TIE)])).unwrap();
+        assert_eq!(outcome, RepairOutcome::Fixed);
+        assert_eq!(fixes, vec!["resolved 1 self-intersections"]);
+        assert_eq!(self_intersections(&fixed), 0);
+        assert!((fixed.unsigned_area() - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn removes_repeated_vertices() {
+        let repeated = polygon(&[(0.0, 0.0), (1.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0), (0.0, 1.0), (0.0, 0.0)]);
+        let (fixed, outcome, fixes) = repair(&multi(vec![repeated])).unwrap();
+        assert_eq!(outcome, RepairOutcome::Fixed);
+        assert_eq!(fixes, vec!["removed 2 repeated vertices"]);
+        assert_eq!(fixed, multi(vec![polygon(&SQUARE)]));
+    }
+
+    #[test]
+    fn drops_zero_area_rings() {
+        let flat = polygon(&[(2.0, 0.0), (3.0, 0.0), (4.0, 0.0), (2.0, 0.0)]);
+        let (fixed, outcome, fixes) = repair(&multi(vec![polygon(&SQUARE), flat.clone()])).unwrap();
+        assert_eq!(outcome, RepairOutcome::Fixed);
+        assert_eq!(fixes, vec!["removed 1 zero-area rings"]);
+        assert_eq!(fixed, multi(vec![polygon(&SQUARE)]));
+
+        // Nothing left: the area is dropped
+        let (_, outcome, _) = repair(&multi(vec![flat])).unwrap();
+        assert_eq!(outcome, RepairOutcome::Dropped);
+    }
+
+    #[test]
+    fn drops_rings_with_non_finite_coordinates() {
+        let broken = polygon(&[(2.0, 0.0), (3.0, f64::NAN), (3.0, 1.0), (2.0, 0.0)]);
+        let (fixed, outcome, fixes) = repair(&multi(vec![polygon(&SQUARE), broken])).unwrap();
+        assert_eq!(outcome, RepairOutcome::Fixed);
+        assert_eq!(fixes, vec!["removed 1 rings with non-finite coordinates"]);
+        assert_eq!(fixed, multi(vec![polygon(&SQUARE)]));
+
+        let hole = LineString::from(vec![(0.2, 0.2), (f64::INFINITY, 0.2), (0.2, 0.8), (0.2, 0.2)]);
+        let (fixed, _, _) = repair(&multi(vec![Polygon::new(LineString::from(SQUARE.to_vec()), vec![hole])])).unwrap();
+        assert_eq!(fixed, multi(vec![polygon(&SQUARE)]));
+    }
+}
@@ -5,9 +5,20 @@ pub mod processing;
 pub mod render;
 pub mod server;
 pub mod masking;
+pub mod cache;
+pub mod report;
+pub mod crs;
+pub mod geometry;
+pub mod validate;
+pub mod color;
+pub mod expr;
+pub mod tiles;
+pub mod search;
+pub mod stats;
 
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
+use std::process::ExitCode;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -28,11 +39,19 @@ enum Commands {
         #[arg(short, long, value_name = "FILE", default_value = "config.toml")]
         config: PathBuf,
     },
+    /// Check the config and input files without generating anything.
+    /// Exits 0 if valid, 1 if checks fail, 2 if the config can't be read
+    Validate {
+        #[arg(short, long, value_name = "FILE", default_value = "config.toml")]
+        config: PathBuf,
+    },
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
     tracing_subscriber::fmt::init();
+    // Boolean op panics are caught and reported; don't print each one
+    geometry::install_quiet_panic_hook();
     
     let cli = Cli::parse();
 
@@ -43,19 +62,21 @@ async fn main() -> anyhow::Result<()> {
             
             // 1. Load Data
             let mut small_areas = data::load_data(&app_config)?;
+            let repairs = geometry::repair_areas(&mut small_areas);
             
-            // 1b. Load and Apply Water Mask (if configured)
-            if let Some(mask_path) = &app_config.input.water_mask {
-                println!("Water masking enabled.");
-                let water_tree = masking::load_water_mask(mask_path)?;
-                masking::mask_small_areas(&mut small_areas, &water_tree);
-            }
+            // 1b. Apply mask layers (water, placement, ...), via the masked geometry cache
+            cache::mask_areas(&app_config, &mut small_areas)?;
+            let mut report = report::GenerationReport::new(&small_areas);
+            report.record_repairs(&repairs);
+            report.record_masking(&small_areas);
             
             // 2. Process Data
-            let dots = processing::process_data(&app_config, small_areas);
+            let dots = processing::process_data(&app_config, &small_areas);
+            report.dots = dots.len();
             
             // 3. Render Tiles
             render::generate_tiles(&app_config, dots)?;
+            report.write(&app_config.output.tile_dir)?;
             
             println!("Generation complete!");
         }
@@ -65,15 +86,32 @@ async fn main() -> anyhow::Result<()> {
             
             // Load data for API interactivity
             println!("Loading data for API...");
-            // We don't strictly need water masking for the API lookup (or maybe we do?)
-            // If we want hover to work only on land, we should mask. 
-            // But usually loading raw areas is faster and fine for "what is this area".
-            let small_areas = data::load_data(&app_config)?;
+            // Raw boundaries are fine for "what is this area"; server.masked_geometry makes
+            // hover match where dots were drawn (cheap once `generate` has filled the cache)
+            let mut small_areas = data::load_data(&app_config)?;
+            geometry::repair_areas(&mut small_areas);
+            if app_config.server.masked_geometry || app_config.server.dynamic_tiles {
+                cache::mask_areas(&app_config, &mut small_areas)?;
+            }
+            // Dynamic tiles draw the same dots `generate` would (placement is seeded)
+            let tiles = if app_config.server.dynamic_tiles {
+                let dots = processing::process_data(&app_config, &small_areas);
+                Some(tiles::TileRenderer::new(&app_config, dots)?)
+            } else {
+                None
+            };
+            if app_config.server.masked_geometry {
+                masking::use_masked_geometry(&app_config, &mut small_areas);
+            }
             
-            server::start_server(app_config, small_areas).await?;
+            server::start_server(app_config, small_areas, tiles).await?;
+        }
+        Commands::Validate { config } => {
+            // Returned rather than `process::exit`ed, so the runtime shuts down normally
+            return Ok(ExitCode::from(validate::run(config)));
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
@@ -1,15 +1,18 @@
-use crate::types::SmallArea;
+use crate::config::{AppConfig, MaskConfig, MaskMode};
+use crate::crs::Crs;
+use crate::geometry::{guarded, is_plausible};
+use crate::types::{MaskedGeometry, PlacementZone, SmallArea};
 use anyhow::{Context, Result, anyhow};
-use geo::{MultiPolygon, Polygon};
+use geo::{MultiPolygon, Polygon, Rect};
 use geo::BooleanOps; // Try top level first, if fails try algorithm::
 use geo::intersects::Intersects;
 use geo::bounding_rect::BoundingRect;
+use geo::Area;
 use rstar::{RTree, RTreeObject, AABB};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::Path;
 use geojson::{GeoJson, Value};
-use std::convert::TryInto;
 use rayon::prelude::*;
 
 // Wrapper for Polygon to implement RTreeObject if needed, 
@@ -17,81 +20,396 @@ use rayon::prelude::*;
 // geo 0.27 might not implement RTreeObject for Polygon by default without feature.
 // Let's implement a wrapper struct for the RTree.
 
-pub struct WaterPolygon(Polygon<f64>);
+/// A mask polygon and its placement weight (unused for subtractive masks).
+pub struct MaskPolygon {
+    polygon: Polygon<f64>,
+    weight: f64,
+    envelope: AABB<[f64; 2]>,
+}
+
+impl MaskPolygon {
+    /// `None` for a polygon with no points (an empty ring), which has no extent to index.
+    fn new(polygon: Polygon<f64>, weight: f64) -> Option<Self> {
+        let bbox = polygon.bounding_rect()?;
+        let envelope = AABB::from_corners([bbox.min().x, bbox.min().y], [bbox.max().x, bbox.max().y]);
+        Some(MaskPolygon { polygon, weight, envelope })
+    }
+}
 
-impl RTreeObject for WaterPolygon {
+impl RTreeObject for MaskPolygon {
     type Envelope = AABB<[f64; 2]>;
 
     fn envelope(&self) -> Self::Envelope {
-        let bbox = self.0.bounding_rect().unwrap();
-        AABB::from_corners([bbox.min().x, bbox.min().y], [bbox.max().x, bbox.max().y])
+        self.envelope
+    }
+}
+
+/// A loaded mask layer: its config and an RTree of its polygons.
+pub struct MaskLayer {
+    pub config: MaskConfig,
+    pub tree: RTree<MaskPolygon>,
+}
+
+/// Loads every mask layer that at least one dataset uses, keyed by mask name.
+pub fn load_masks(config: &AppConfig) -> Result<HashMap<String, MaskLayer>> {
+    let used: std::collections::HashSet<String> = config.processing.datasets.keys()
+        .flat_map(|name| config.dataset_masks(name))
+        .collect();
+
+    let mut layers = HashMap::new();
+    for mask in config.masks() {
+        if !used.contains(&mask.name) {
+            println!("Mask '{}' is not used by any dataset; skipping", mask.name);
+            continue;
+        }
+        layers.insert(mask.name.clone(), load_mask(mask)?);
     }
+    Ok(layers)
 }
 
-pub fn load_water_mask(path: &Path) -> Result<RTree<WaterPolygon>> {
-    println!("Loading water mask from {:?}...", path);
-    let file = File::open(path).with_context(|| format!("Failed to open water mask: {:?}", path))?;
+pub fn load_mask(mask: MaskConfig) -> Result<MaskLayer> {
+    println!("Loading mask '{}' ({:?}) from {:?}...", mask.name, mask.mode, mask.path);
+    let extension = mask.path.extension()
+        .and_then(|e| e.to_str())
+        .map(|s| s.to_lowercase())
+        .ok_or_else(|| anyhow!("Mask '{}' file has no extension", mask.name))?;
+
+    let polygons = match extension.as_str() {
+        "shp" => read_shapefile_polygons(&mask)?,
+        "json" | "geojson" => read_geojson_polygons(&mask)?,
+        _ => return Err(anyhow!("Unsupported mask format for '{}': {}", mask.name, extension)),
+    };
+
+    println!("Building spatial index for {} polygons of mask '{}'...", polygons.len(), mask.name);
+    Ok(MaskLayer { config: mask, tree: RTree::bulk_load(polygons) })
+}
+
+fn checked_weight(mask: &MaskConfig, value: Option<f64>, raw: &dyn std::fmt::Debug) -> Result<f64> {
+    value
+        .filter(|w| w.is_finite() && *w >= 0.0)
+        .ok_or_else(|| anyhow!("Mask '{}' {:?}: invalid weight {:?} (expected a non-negative number)", mask.name, mask.path, raw))
+}
+
+fn read_geojson_polygons(mask: &MaskConfig) -> Result<Vec<MaskPolygon>> {
+    let path = &mask.path;
+    let file = File::open(path).with_context(|| format!("Failed to open mask '{}': {:?}", mask.name, path))?;
     let reader = BufReader::new(file);
-    let geojson = GeoJson::from_reader(reader).context("Failed to parse Water Mask GeoJSON")?;
+    let geojson = GeoJson::from_reader(reader).with_context(|| format!("Failed to parse mask '{}' GeoJSON", mask.name))?;
 
     let collection = match geojson {
         GeoJson::FeatureCollection(fc) => fc,
-        _ => return Err(anyhow!("Water mask must be a FeatureCollection")),
+        _ => return Err(anyhow!("Mask '{}' must be a FeatureCollection", mask.name)),
+    };
+
+    let crs = match &mask.crs {
+        Some(name) => Crs::from_name(name)?,
+        None => match Crs::from_geojson_members(collection.foreign_members.as_ref())? {
+            Some(crs) => crs,
+            None => Crs::resolve(None, path)?,
+        },
     };
 
     let mut polygons = Vec::new();
 
     for feature in collection.features {
+        let weight = match mask.weight_property.as_deref().and_then(|name| feature.property(name)) {
+            Some(value) => checked_weight(mask, value.as_f64(), value)?,
+            None => mask.default_weight,
+        };
         if let Some(geom) = feature.geometry {
              match geom.value {
                  Value::Polygon(_) | Value::MultiPolygon(_) => {
                      let geo_geom: geo::Geometry<f64> = geom.value.try_into()
                         .map_err(|e| anyhow!("Failed to convert geometry: {:?}", e))?;
                      
-                     match geo_geom {
-                         geo::Geometry::Polygon(p) => polygons.push(WaterPolygon(p)),
-                         geo::Geometry::MultiPolygon(mp) => {
-                             for p in mp {
-                                 polygons.push(WaterPolygon(p));
-                             }
-                         },
-                         _ => {},
-                     }
+                     let mut mp = match geo_geom {
+                         geo::Geometry::Polygon(p) => MultiPolygon::new(vec![p]),
+                         geo::Geometry::MultiPolygon(mp) => mp,
+                         _ => continue,
+                     };
+                     crs.reproject(&mut mp);
+                     polygons.extend(mp.into_iter().filter_map(|polygon| MaskPolygon::new(polygon, weight)));
                  },
                  _ => {},
              }
         }
     }
 
-    println!("Building spatial index for {} water polygons...", polygons.len());
-    let tree = RTree::bulk_load(polygons);
-    Ok(tree)
+    Ok(polygons)
+}
+
+fn read_shapefile_polygons(mask: &MaskConfig) -> Result<Vec<MaskPolygon>> {
+    use shapefile::dbase::FieldValue;
+
+    let mut reader = shapefile::Reader::from_path(&mask.path)
+        .with_context(|| format!("Failed to open mask '{}': {:?}", mask.name, mask.path))?;
+    let crs = Crs::resolve(mask.crs.as_deref(), &mask.path)?;
+
+    let mut polygons = Vec::new();
+    for result in reader.iter_shapes_and_records() {
+        let (shape, record) = result?;
+
+        let weight = match mask.weight_property.as_deref().and_then(|name| record.get(name)) {
+            Some(value) => {
+                let number = match value {
+                    FieldValue::Numeric(n) => *n,
+                    FieldValue::Float(f) => f.map(f64::from),
+                    FieldValue::Double(d) => Some(*d),
+                    FieldValue::Integer(i) => Some(f64::from(*i)),
+                    FieldValue::Character(Some(s)) => s.trim().parse().ok(),
+                    _ => None,
+                };
+                checked_weight(mask, number, value)?
+            }
+            None => mask.default_weight,
+        };
+
+        let mut mp: MultiPolygon<f64> = match shape {
+            shapefile::Shape::Polygon(polygon) => polygon.into(),
+            shapefile::Shape::PolygonM(polygon) => polygon.into(),
+            shapefile::Shape::PolygonZ(polygon) => polygon.into(),
+            _ => continue, // Skip non-polygon shapes
+        };
+        crs.reproject(&mut mp);
+        polygons.extend(mp.into_iter().filter_map(|polygon| MaskPolygon::new(polygon, weight)));
+    }
+    Ok(polygons)
 }
 
-pub fn mask_small_areas(areas: &mut Vec<SmallArea>, water_tree: &RTree<WaterPolygon>) {
-    println!("Masking water from {} areas...", areas.len());
+/// Applies each dataset's masks to the areas carrying that dataset, storing the result
+/// in `SmallArea::masked` under the dataset's mask key.
+pub fn apply_masks(config: &AppConfig, areas: &mut [SmallArea], layers: &HashMap<String, MaskLayer>) {
+    println!("Masking {} areas...", areas.len());
+
+    // Dataset -> (mask key, layers), resolved once up front
+    let plans: HashMap<&String, (String, Vec<&MaskLayer>)> = config.processing.datasets.keys()
+        .map(|name| {
+            let masks = config.dataset_masks(name).iter().filter_map(|m| layers.get(m)).collect();
+            (name, (config.mask_key(name), masks))
+        })
+        .collect();
 
     areas.par_iter_mut().for_each(|area| {
-        let area_bbox = area.geometry.bounding_rect().unwrap();
-        let area_aabb = AABB::from_corners(
-            [area_bbox.min().x, area_bbox.min().y], 
-            [area_bbox.max().x, area_bbox.max().y]
+        for dataset_name in area.population_data.keys() {
+            let Some((key, masks)) = plans.get(dataset_name) else { continue };
+            if masks.is_empty() || area.masked.contains_key(key) {
+                continue;
+            }
+            let masked = mask_geometry(&area.geometry, masks, config.processing.min_retained_fraction);
+            area.masked.insert(key.clone(), masked);
+        }
+    });
+}
+
+/// Prints a summary of how masking went: placement coverage, unmasked fallbacks and failures.
+pub fn print_masking_summary(areas: &[SmallArea]) {
+    let uniform = areas.iter()
+        .flat_map(|a| a.masked.values())
+        .filter(|m| m.placement.is_empty())
+        .count();
+    let placed = areas.iter().flat_map(|a| a.masked.values()).count() - uniform;
+    if placed > 0 && uniform > 0 {
+        println!("  {} masked areas have no placement polygons; their dots are placed uniformly", uniform);
+    }
+
+    let unmasked: Vec<&str> = areas.iter()
+        .filter(|a| a.masked.values().any(|m| m.unmasked_fallback.is_some()))
+        .map(|a| a.id.as_str())
+        .collect();
+    if !unmasked.is_empty() {
+        println!(
+            "  WARNING: masking would erase too much of {} areas, which keep their unmasked boundary (e.g. {})",
+            unmasked.len(), unmasked.iter().take(10).copied().collect::<Vec<_>>().join(", ")
         );
+    }
 
-        // Find intersecting water polygons
-        let water_candidates: Vec<&WaterPolygon> = water_tree.locate_in_envelope_intersecting(&area_aabb).collect();
+    let problems: Vec<&String> = areas.iter().filter(|a| a.masked.values().any(|m| m.issue.is_some())).map(|a| &a.id).collect();
+    if !problems.is_empty() {
+        let sample: Vec<&str> = problems.iter().take(10).map(|id| id.as_str()).collect();
+        println!(
+            "  WARNING: masking fell back for {} areas (e.g. {}); see the generation report",
+            problems.len(), sample.join(", ")
+        );
+    }
+}
 
-        if water_candidates.is_empty() {
-            return;
+/// Subtracts the `subtract` masks from an area, then clips the `constrain` / `weight`
+/// masks to what is left to get its placement zones.
+fn mask_geometry(original: &MultiPolygon<f64>, masks: &[&MaskLayer], min_retained_fraction: f64) -> MaskedGeometry {
+    let mut geometry = original.clone();
+    let mut issues = Vec::new();
+    for mask in masks.iter().filter(|m| m.config.mode == MaskMode::Subtract) {
+        if let Some(issue) = subtract_mask(&mut geometry, &mask.tree) {
+            issues.push(format!("mask '{}': {}", mask.config.name, issue));
         }
+    }
+
+    // Don't let masking erase (most of) an area and lose its population
+    let mut unmasked_fallback = None;
+    let original_area = original.unsigned_area();
+    if original_area > 0.0 {
+        let retained = geometry.unsigned_area() / original_area;
+        if geometry.0.is_empty() || retained <= 0.0 || retained < min_retained_fraction {
+            geometry = original.clone();
+            unmasked_fallback = Some(retained.max(0.0));
+        }
+    }
+
+    let mut placement = Vec::new();
+    let Some(area_bbox) = geometry.bounding_rect() else {
+        return MaskedGeometry { geometry, placement, issue: join_issues(issues), unmasked_fallback };
+    };
+    let area_aabb = AABB::from_corners(
+        [area_bbox.min().x, area_bbox.min().y],
+        [area_bbox.max().x, area_bbox.max().y]
+    );
 
-        // Subtract each water polygon
-        for water_poly in water_candidates {
-            if area.geometry.intersects(&water_poly.0) {
-                let water_mp = MultiPolygon::new(vec![water_poly.0.clone()]);
-                let new_geo = area.geometry.difference(&water_mp);
-                area.geometry = new_geo;
+    let mut constrained = false;
+    let mut background: Option<f64> = None;
+    for mask in masks.iter().filter(|m| m.config.mode != MaskMode::Subtract) {
+        let before = placement.len();
+        let mut failed = 0;
+        for candidate in mask.tree.locate_in_envelope_intersecting(&area_aabb) {
+            if candidate.weight <= 0.0 || !geometry.intersects(&candidate.polygon) {
+                continue;
             }
+            let clipped = guarded(|| geometry.intersection(&MultiPolygon::new(vec![candidate.polygon.clone()])));
+            match clipped {
+                Some(clipped) if is_plausible(&clipped) => {
+                    if !clipped.0.is_empty() {
+                        placement.push(PlacementZone { geometry: clipped, weight: candidate.weight });
+                    }
+                }
+                _ => failed += 1,
+            }
+        }
+        if failed > 0 {
+            issues.push(format!("mask '{}': {} polygons could not be clipped to the area and were ignored", mask.config.name, failed));
+        }
+        if placement.len() > before {
+            match mask.config.mode {
+                MaskMode::Constrain => constrained = true,
+                _ => {
+                    let weight = mask.config.background_weight;
+                    background = Some(background.map_or(weight, |b| b.min(weight)));
+                }
+            }
+        }
+    }
+
+    // Weight masks add density on top of the whole area at background_weight
+    if let (false, Some(weight)) = (constrained, background) {
+        if weight > 0.0 {
+            placement.push(PlacementZone { geometry: geometry.clone(), weight });
+        }
+    }
+
+    MaskedGeometry { geometry, placement, issue: join_issues(issues), unmasked_fallback }
+}
+
+fn join_issues(issues: Vec<String>) -> Option<String> {
+    if issues.is_empty() { None } else { Some(issues.join("; ")) }
+}
+
+/// Subtracts one mask layer from an area: candidate polygons reaching past the area's
+/// bounding box are clipped to it, then all are unioned and removed with a single `difference`. If that panics or
+/// gives a broken result, falls back to subtracting polygon by polygon, skipping any that
+/// fail. Returns a description of what went wrong, if anything did.
+fn subtract_mask(geometry: &mut MultiPolygon<f64>, tree: &RTree<MaskPolygon>) -> Option<String> {
+    let area_bbox = geometry.bounding_rect()?;
+    let area_aabb = AABB::from_corners(
+        [area_bbox.min().x, area_bbox.min().y], 
+        [area_bbox.max().x, area_bbox.max().y]
+    );
+
+    // Find intersecting mask polygons
+    let candidates: Vec<&Polygon<f64>> = tree.locate_in_envelope_intersecting(&area_aabb)
+        .map(|m| &m.polygon)
+        .filter(|polygon| geometry.intersects(*polygon))
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+
+    let original_area = geometry.unsigned_area();
+    let fast = guarded(|| {
+        let clip = MultiPolygon::new(vec![area_bbox.to_polygon()]);
+        // Clipping a large polygon (sea, lake) leaves the union only the vertices near the
+        // area; polygons already inside the box would come out unchanged, so skip the op
+        let pieces: Vec<MultiPolygon<f64>> = candidates.iter()
+            .map(|polygon| {
+                let polygon = MultiPolygon::new(vec![(*polygon).clone()]);
+                match polygon.bounding_rect() {
+                    Some(rect) if within(&rect, &area_bbox) => polygon,
+                    _ => clip.intersection(&polygon),
+                }
+            })
+            .collect();
+        geometry.difference(&union_all(pieces))
+    });
+    let reason = match fast {
+        Some(result) if is_plausible(&result) && result.unsigned_area() <= original_area * (1.0 + 1e-9) => {
+            *geometry = result;
+            return None;
+        }
+        Some(_) => "union-then-difference produced invalid geometry",
+        None => "union-then-difference failed",
+    };
+
+    let mut skipped = 0;
+    for polygon in &candidates {
+        let before = geometry.unsigned_area();
+        let mask_mp = MultiPolygon::new(vec![(*polygon).clone()]);
+        match guarded(|| geometry.difference(&mask_mp)) {
+            Some(result) if is_plausible(&result) && result.unsigned_area() <= before * (1.0 + 1e-9) => {
+                *geometry = result;
+            }
+            _ => skipped += 1,
+        }
+    }
+
+    Some(if skipped == 0 {
+        format!("{}; subtracted polygon by polygon", reason)
+    } else {
+        format!("{}; {} of {} mask polygons could not be subtracted", reason, skipped, candidates.len())
+    })
+}
+
+fn within(inner: &Rect<f64>, outer: &Rect<f64>) -> bool {
+    inner.min().x >= outer.min().x && inner.min().y >= outer.min().y
+        && inner.max().x <= outer.max().x && inner.max().y <= outer.max().y
+}
+
+/// Unions geometries pairwise, keeping the operands of each step of similar size.
+fn union_all(mut pieces: Vec<MultiPolygon<f64>>) -> MultiPolygon<f64> {
+    pieces.retain(|p| !p.0.is_empty());
+    while pieces.len() > 1 {
+        pieces = pieces.chunks(2)
+            .map(|pair| match pair {
+                [a, b] => a.union(b),
+                [a] => a.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    pieces.pop().unwrap_or_else(|| MultiPolygon::new(vec![]))
+}
+
+/// Swaps each area's boundary for the land its dots were drawn in, so point lookups
+/// match the tiles. Areas whose datasets use different mask sets get the union, and
+/// areas with an unmasked dataset keep their full boundary.
+pub fn use_masked_geometry(config: &AppConfig, areas: &mut [SmallArea]) {
+    let unmasked: Vec<&String> = config.processing.datasets.keys()
+        .filter(|name| config.mask_key(name).is_empty())
+        .collect();
+
+    areas.par_iter_mut().for_each(|area| {
+        if unmasked.iter().any(|name| area.population_data.contains_key(*name)) {
+            area.masked.clear();
+            return;
         }
+        let mut masked = std::mem::take(&mut area.masked).into_values();
+        let Some(first) = masked.next() else { return };
+        area.geometry = masked.fold(first.geometry, |acc, m| acc.union(&m.geometry));
     });
 }
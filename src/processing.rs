@@ -1,14 +1,20 @@
-use crate::config::AppConfig;
+use crate::config::{AppConfig, DatasetConfig, gain_category, loss_category};
 use crate::types::{Dot, SmallArea};
+use geo::algorithm::area::Area;
 use geo::algorithm::bounding_rect::BoundingRect;
 use geo::algorithm::contains::Contains;
 use geo::{MultiPolygon, Point, Rect};
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use rayon::prelude::*;
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
-use rand::seq::SliceRandom;
 
-pub fn process_data(config: &AppConfig, small_areas: Vec<SmallArea>) -> Vec<Dot> {
+/// Generates every dot. Placement is seeded from `processing.seed`, the area and the layer,
+/// so the same inputs always give the same dots (`serve` relies on this to render tiles
+/// that match the ones `generate` wrote).
+pub fn process_data(config: &AppConfig, small_areas: &[SmallArea]) -> Vec<Dot> {
     println!("Processing {} areas to generate dots...", small_areas.len());
 
     let dots: Vec<Dot> = small_areas.par_iter().flat_map(|area| {
@@ -17,13 +23,9 @@ pub fn process_data(config: &AppConfig, small_areas: Vec<SmallArea>) -> Vec<Dot>
 
     println!("Generated {} dots total.", dots.len());
     
-    // Shuffle is implicit if we want random rendering order, but since we tile them later,
-    // the order within the tile matters if they overlap.
-    // Use rand::shuffle if needed, but for now simple collection is fine.
-    // Actually, to avoid "z-ordering" bias where one race is always on top, we SHOULD shuffle.
-    let mut rng = rand::thread_rng();
+    // Shuffle to avoid "z-ordering" bias where one category is always drawn on top
+    let mut rng = StdRng::seed_from_u64(config.processing.seed);
     let mut dots = dots;
-    use rand::seq::SliceRandom;
     dots.shuffle(&mut rng);
 
     dots
@@ -31,59 +33,148 @@ pub fn process_data(config: &AppConfig, small_areas: Vec<SmallArea>) -> Vec<Dot>
 
 fn generate_dots_for_area(config: &AppConfig, area: &SmallArea) -> Vec<Dot> {
     let mut area_dots = Vec::new();
-    let bbox = area.geometry.bounding_rect().unwrap();
 
-    for (dataset_name, dataset_config) in &config.processing.datasets {
+    // Sorted so the output order doesn't depend on HashMap iteration
+    let mut datasets: Vec<(&String, &DatasetConfig)> = config.processing.datasets.iter().collect();
+    datasets.sort_by_key(|(name, _)| *name);
+
+    for (dataset_name, dataset_config) in datasets {
         // Get population data for this dataset
         let pop_data = match area.population_data.get(dataset_name) {
             Some(d) => d,
             None => continue,
         };
+        let sampler = PointSampler::new(area, &config.mask_key(dataset_name));
+        let mut rng = area_rng(config, area, dataset_name);
 
-        // 1. Calculate Distributable "Total" for Not Stated redistribution
-        let not_stated_count = if let Some(_ns_config) = &dataset_config.not_stated {
-             *pop_data.get("Not Stated").unwrap_or(&0) as f64
-        } else {
-            0.0
-        };
-        
-        let mut total_known = 0.0;
-        for cat in &dataset_config.categories {
-            total_known += *pop_data.get(&cat.name).unwrap_or(&0) as f64;
-        }
-
-        // If total known is 0, we can't distribute not stated properly, so just define 0.
-        // Or if we have no Not Stated config, we just ignore redistribution.
-        
-        for cat in &dataset_config.categories {
-            let known_count = *pop_data.get(&cat.name).unwrap_or(&0) as f64;
-            
-            let final_count = if total_known > 0.0 {
-                let proportion = known_count / total_known;
-                let additional = proportion * not_stated_count;
-                (known_count + additional).round() as u32
-            } else {
-                known_count as u32
-            };
-
+        for (category, final_count) in redistribute(dataset_config, pop_data) {
             for _ in 0..final_count {
-                if let Some(pt) = generate_random_point_in_poly(&area.geometry, &bbox) {
+                if let Some(pt) = sampler.sample(&mut rng) {
                      area_dots.push(Dot {
                          point: pt,
                          dataset: dataset_name.clone(),
-                         category: cat.name.clone(),
+                         category: category.clone(),
                      });
                 }
             }
         }
+
+        // Change layers: one dot per person gained or lost in each category since each earlier census
+        for compare in dataset_config.compare.iter().filter(|c| c.change_layer) {
+            let Some(previous) = area.previous_data.get(dataset_name).and_then(|p| p.get(&compare.previous_year)) else {
+                continue;
+            };
+            let layer = dataset_config.change_layer_name(dataset_name, compare);
+            let mut rng = area_rng(config, area, &layer);
+            let before: HashMap<String, u32> = redistribute(dataset_config, previous).into_iter().collect();
+            for (category, now) in redistribute(dataset_config, pop_data) {
+                let then = before.get(&category).copied().unwrap_or(0);
+                let (label, count) = if now >= then {
+                    (gain_category(&category), now - then)
+                } else {
+                    (loss_category(&category), then - now)
+                };
+                for _ in 0..count {
+                    if let Some(pt) = sampler.sample(&mut rng) {
+                         area_dots.push(Dot {
+                             point: pt,
+                             dataset: layer.clone(),
+                             category: label.clone(),
+                         });
+                    }
+                }
+            }
+        }
     }
 
     area_dots
 }
 
+/// Random source for one area's dots in one tile layer.
+fn area_rng(config: &AppConfig, area: &SmallArea, layer: &str) -> StdRng {
+    let mut hasher = Sha256::new();
+    hasher.update(config.processing.seed.to_le_bytes());
+    for part in [&area.level, &area.id, layer] {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    let mut seed = [0u8; 32];
+    seed.copy_from_slice(&hasher.finalize());
+    StdRng::from_seed(seed)
+}
+
+
+/// Per-category counts with the "Not Stated" count shared out in proportion to the known categories.
+pub fn redistribute(dataset_config: &DatasetConfig, pop_data: &HashMap<String, u32>) -> Vec<(String, u32)> {
+    let not_stated_count = if dataset_config.not_stated.is_some() {
+        *pop_data.get("Not Stated").unwrap_or(&0) as f64
+    } else {
+        0.0
+    };
+
+    let total_known: f64 = dataset_config.categories.iter()
+        .map(|cat| *pop_data.get(&cat.name).unwrap_or(&0) as f64)
+        .sum();
+
+    // If total known is 0, we can't distribute not stated properly, so just define 0.
+    dataset_config.categories.iter().map(|cat| {
+        let known_count = *pop_data.get(&cat.name).unwrap_or(&0) as f64;
+        let final_count = if total_known > 0.0 {
+            let proportion = known_count / total_known;
+            (known_count + proportion * not_stated_count).round() as u32
+        } else {
+            known_count as u32
+        };
+        (cat.name.clone(), final_count)
+    }).collect()
+}
+
+/// Draws dot positions for one area: uniformly over its (masked) geometry, or, with
+/// placement masks, from its zones in proportion to zone area times weight.
+struct PointSampler<'a> {
+    zones: Vec<(&'a MultiPolygon<f64>, Rect<f64>)>,
+    cumulative: Vec<f64>,
+}
+
+impl<'a> PointSampler<'a> {
+    fn new(area: &'a SmallArea, mask_key: &str) -> Self {
+        let (geometry, placement) = match area.masked.get(mask_key) {
+            Some(masked) => (&masked.geometry, masked.placement.as_slice()),
+            None => (&area.geometry, &[][..]),
+        };
+
+        let mut zones = Vec::new();
+        let mut cumulative = Vec::new();
+        let mut total = 0.0;
+        for zone in placement {
+            let mass = zone.geometry.unsigned_area() * zone.weight;
+            if let (Some(bbox), true) = (zone.geometry.bounding_rect(), mass > 0.0) {
+                total += mass;
+                zones.push((&zone.geometry, bbox));
+                cumulative.push(total);
+            }
+        }
+
+        // No usable zones: fall back to the whole area
+        if zones.is_empty() {
+            if let Some(bbox) = geometry.bounding_rect() {
+                zones.push((geometry, bbox));
+                cumulative.push(1.0);
+            }
+        }
+        PointSampler { zones, cumulative }
+    }
+
+    fn sample(&self, rng: &mut impl Rng) -> Option<Point<f64>> {
+        let total = *self.cumulative.last()?;
+        let pick = rng.gen_range(0.0..total);
+        let index = self.cumulative.partition_point(|&c| c <= pick).min(self.zones.len() - 1);
+        let (geometry, bbox) = &self.zones[index];
+        generate_random_point_in_poly(geometry, bbox, rng)
+    }
+}
 
-fn generate_random_point_in_poly(poly: &MultiPolygon<f64>, bbox: &Rect<f64>) -> Option<Point<f64>> {
-    let mut rng = rand::thread_rng();
+fn generate_random_point_in_poly(poly: &MultiPolygon<f64>, bbox: &Rect<f64>, rng: &mut impl Rng) -> Option<Point<f64>> {
     // Simple rejection sampling
     // Try 100 times then give up (corner case: very thin polygons)
     for _ in 0..100 {
@@ -1,4 +1,5 @@
-use crate::config::AppConfig;
+use crate::color;
+use crate::config::{AppConfig, TileLayer};
 use crate::types::Dot;
 use anyhow::{Context, Result};
 use image::{ImageBuffer, Rgba, RgbaImage};
@@ -6,11 +7,10 @@ use rayon::prelude::*;
 use std::collections::HashMap;
 use std::f64::consts::PI;
 use std::fs;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 // Constants for Web Mercator
-const TILE_SIZE: u32 = 256;
+pub const TILE_SIZE: u32 = 256;
 
 pub fn generate_tiles(config: &AppConfig, dots: Vec<Dot>) -> Result<()> {
     println!("Generating tiles from min_zoom {} to max_zoom {}...", config.output.min_zoom, config.output.max_zoom);
@@ -24,17 +24,15 @@ pub fn generate_tiles(config: &AppConfig, dots: Vec<Dot>) -> Result<()> {
         dots_by_dataset.entry(dot.dataset.clone()).or_default().push(dot);
     }
     
-    // Process each dataset
+    let layers = config.tile_layers();
+
+    // Process each dataset (and change layer)
     for (dataset_name, dataset_dots) in dots_by_dataset {
         println!("Rendering dataset: {}", dataset_name);
         
-        let colors: Arc<HashMap<String, Rgba<u8>>> = Arc::new(
-            config.processing.datasets.get(&dataset_name)
-                .expect("Dataset config missing")
-                .categories.iter()
-                .map(|c| (c.name.clone(), hex_to_rgba(&c.color)))
-                .collect()
-        );
+        let layer = layers.iter().find(|l| l.name == dataset_name)
+            .expect("Dataset config missing");
+        let colors: Arc<HashMap<String, Rgba<u8>>> = Arc::new(layer_colors(layer)?);
         
         let dataset_dots = Arc::new(dataset_dots);
         let dataset_name = Arc::new(dataset_name);
@@ -47,6 +45,13 @@ pub fn generate_tiles(config: &AppConfig, dots: Vec<Dot>) -> Result<()> {
     Ok(())
 }
 
+/// Category name -> pixel colour for one tile layer.
+pub fn layer_colors(layer: &TileLayer) -> Result<HashMap<String, Rgba<u8>>> {
+    layer.categories.iter()
+        .map(|c| color::parse_color(&c.color).map(|rgba| (c.name.clone(), rgba)))
+        .collect()
+}
+
 fn render_zoom_level(
     config: &AppConfig,
     dataset_name: &str,
@@ -89,16 +94,8 @@ fn render_zoom_level(
 }
 
 
-fn hex_to_rgba(hex: &str) -> Rgba<u8> {
-    let hex = hex.trim_start_matches('#');
-    let r = u8::from_str_radix(&hex[0..2], 16).unwrap_or(0);
-    let g = u8::from_str_radix(&hex[2..4], 16).unwrap_or(0);
-    let b = u8::from_str_radix(&hex[4..6], 16).unwrap_or(0);
-    Rgba([r, g, b, 255])
-}
-
 // Coordinate conversions
-fn lat_lon_to_tile_pixel(lat: f64, lon: f64, zoom: u8) -> (u32, u32, u32, u32) {
+pub fn lat_lon_to_tile_pixel(lat: f64, lon: f64, zoom: u8) -> (u32, u32, u32, u32) {
     let n = 2.0_f64.powi(zoom as i32); // Use powi for integer power
     let x_t = (lon + 180.0) / 360.0 * n;
     let lat_rad = lat.to_radians();
@@ -112,3 +109,11 @@ fn lat_lon_to_tile_pixel(lat: f64, lon: f64, zoom: u8) -> (u32, u32, u32, u32) {
     
     (tx, ty, px, py)
 }
+
+/// Lon/lat bounds of a tile: (west, south, east, north).
+pub fn tile_bounds(zoom: u8, x: u32, y: u32) -> (f64, f64, f64, f64) {
+    let n = 2.0_f64.powi(zoom as i32);
+    let lon = |tx: f64| tx / n * 360.0 - 180.0;
+    let lat = |ty: f64| (PI * (1.0 - 2.0 * ty / n)).sinh().atan().to_degrees();
+    (lon(x as f64), lat(y as f64 + 1.0), lon(x as f64 + 1.0), lat(y as f64))
+}
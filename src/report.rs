@@ -0,0 +1,92 @@
+use crate::geometry::GeometryRepair;
+use crate::types::SmallArea;
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File `generate` writes into `tile_dir` describing the run.
+pub const REPORT_FILE: &str = "report.json";
+
+/// What happened during a `generate` run, for the areas that needed attention.
+#[derive(Debug, Serialize)]
+pub struct GenerationReport {
+    pub generated_at: u64, // Unix seconds
+    pub areas: usize,
+    pub dots: usize,
+    // Areas whose boundaries were repaired, dropped or left invalid on load
+    pub geometry: Vec<AreaIssue>,
+    // Areas where masking needed a fallback
+    pub masking: Vec<AreaIssue>,
+    // Areas masking would have (nearly) erased, drawn with their unmasked boundary
+    pub unmasked: Vec<AreaIssue>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AreaIssue {
+    pub id: String,
+    pub level: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub masks: Option<String>, // Mask set, e.g. "park+water"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outcome: Option<&'static str>, // Geometry repairs: fixed, dropped or invalid
+    pub issue: String,
+}
+
+impl GenerationReport {
+    pub fn new(areas: &[SmallArea]) -> Self {
+        let generated_at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        GenerationReport { generated_at, areas: areas.len(), dots: 0, geometry: Vec::new(), masking: Vec::new(), unmasked: Vec::new() }
+    }
+
+    pub fn record_repairs(&mut self, repairs: &[GeometryRepair]) {
+        self.geometry.extend(repairs.iter().map(|repair| AreaIssue {
+            id: repair.id.clone(),
+            level: repair.level.clone(),
+            masks: None,
+            outcome: Some(repair.outcome.label()),
+            issue: repair.detail.clone(),
+        }));
+    }
+
+    pub fn record_masking(&mut self, areas: &[SmallArea]) {
+        for area in areas {
+            for (masks, masked) in &area.masked {
+                if let Some(retained) = masked.unmasked_fallback {
+                    self.unmasked.push(AreaIssue {
+                        id: area.id.clone(),
+                        level: area.level.clone(),
+                        masks: Some(masks.clone()),
+                        outcome: None,
+                        issue: format!("masking would keep {:.1}% of the area", retained * 100.0),
+                    });
+                }
+                if let Some(issue) = &masked.issue {
+                    self.masking.push(AreaIssue {
+                        id: area.id.clone(),
+                        level: area.level.clone(),
+                        masks: Some(masks.clone()),
+                        outcome: None,
+                        issue: issue.clone(),
+                    });
+                }
+            }
+        }
+        for issues in [&mut self.masking, &mut self.unmasked] {
+            issues.sort_by(|a, b| (&a.level, &a.id, &a.masks).cmp(&(&b.level, &b.id, &b.masks)));
+        }
+    }
+
+    pub fn write(&self, tile_dir: &Path) -> Result<()> {
+        fs::create_dir_all(tile_dir).context("Failed to create tile directory")?;
+        let path = tile_dir.join(REPORT_FILE);
+        let json = serde_json::to_string_pretty(self)?;
+        fs::write(&path, json).with_context(|| format!("Failed to write generation report {:?}", path))?;
+        println!(
+            "Generation report written to {:?} ({} geometry repairs, {} masking issues, {} unmasked areas)",
+            path, self.geometry.len(), self.masking.len(), self.unmasked.len()
+        );
+        Ok(())
+    }
+}
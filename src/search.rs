@@ -0,0 +1,139 @@
+use serde::Serialize;
+
+// Queries shorter than this only match exactly, by prefix or as a substring
+const MIN_FUZZY_LENGTH: usize = 3;
+
+/// How a search term matched, best first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    Substring,
+    Fuzzy,
+}
+
+/// A search term, normalised once and matched against many ids and names.
+pub struct SearchQuery {
+    text: String,
+    chars: Vec<char>,
+}
+
+impl SearchQuery {
+    pub fn new(query: &str) -> Self {
+        let text = query.trim().to_lowercase();
+        let chars = text.chars().collect();
+        SearchQuery { text, chars }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    /// Matches one searchable text (an id or a name), ignoring case. Returns the kind of
+    /// match and, for fuzzy matches, the edit distance. Fuzzy matching allows one edit per
+    /// four characters of the query, against the whole text or its start.
+    pub fn matches(&self, text: &str) -> Option<(MatchKind, usize)> {
+        if self.is_empty() {
+            return None;
+        }
+        let text = text.to_lowercase();
+        if text == self.text {
+            return Some((MatchKind::Exact, 0));
+        }
+        if text.starts_with(&self.text) {
+            return Some((MatchKind::Prefix, 0));
+        }
+        if text.contains(&self.text) {
+            return Some((MatchKind::Substring, 0));
+        }
+
+        if self.chars.len() < MIN_FUZZY_LENGTH {
+            return None;
+        }
+        let text: Vec<char> = text.chars().collect();
+        let max_edits = (self.chars.len() / 4).max(1);
+        let start = &text[..text.len().min(self.chars.len())];
+        let distance = edit_distance(&self.chars, &text).min(edit_distance(&self.chars, start));
+        (distance <= max_edits).then_some((MatchKind::Fuzzy, distance))
+    }
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distance(a: &str, b: &str) -> usize {
+        let a: Vec<char> = a.chars().collect();
+        let b: Vec<char> = b.chars().collect();
+        edit_distance(&a, &b)
+    }
+
+    #[test]
+    fn edit_distance_counts_single_edits() {
+        assert_eq!(distance("", ""), 0);
+        assert_eq!(distance("", "abc"), 3);
+        assert_eq!(distance("abc", ""), 3);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("ballymun", "balymun"), 1);
+        assert_eq!(distance("ballymun", "ballymunn"), 1);
+        assert_eq!(distance("ballymun", "bellymun"), 1);
+        assert_eq!(distance("áras", "aras"), 1);
+    }
+
+    #[test]
+    fn ranks_exact_prefix_substring_fuzzy() {
+        let query = SearchQuery::new("  Ballymun ");
+        assert_eq!(query.matches("BALLYMUN"), Some((MatchKind::Exact, 0)));
+        assert_eq!(query.matches("Ballymun A"), Some((MatchKind::Prefix, 0)));
+        assert_eq!(query.matches("North Ballymun"), Some((MatchKind::Substring, 0)));
+        assert_eq!(query.matches("Balymun"), Some((MatchKind::Fuzzy, 1)));
+        // Fuzzy against the start of the text too
+        assert_eq!(query.matches("Ballymum C, Dublin City"), Some((MatchKind::Fuzzy, 1)));
+        assert!(MatchKind::Exact < MatchKind::Prefix);
+        assert!(MatchKind::Prefix < MatchKind::Substring);
+        assert!(MatchKind::Substring < MatchKind::Fuzzy);
+    }
+
+    #[test]
+    fn fuzzy_edits_scale_with_query_length() {
+        // One edit per four characters, at least one
+        let short = SearchQuery::new("cork");
+        assert_eq!(short.matches("york"), Some((MatchKind::Fuzzy, 1)));
+        assert_eq!(short.matches("yolk"), None);
+        let long = SearchQuery::new("ballyfermot");
+        assert_eq!(long.matches("balyfermott"), Some((MatchKind::Fuzzy, 2)));
+        assert_eq!(long.matches("balyfermot x"), Some((MatchKind::Fuzzy, 2)));
+        assert_eq!(long.matches("bolyfarmat"), None);
+    }
+
+    #[test]
+    fn short_queries_are_not_fuzzy() {
+        assert_eq!(SearchQuery::new("ab").matches("xab"), Some((MatchKind::Substring, 0)));
+        assert_eq!(SearchQuery::new("ab").matches("ac"), None);
+        assert_eq!(SearchQuery::new("abc").matches("abd"), Some((MatchKind::Fuzzy, 1)));
+    }
+
+    #[test]
+    fn empty_query_matches_nothing() {
+        let query = SearchQuery::new("   ");
+        assert!(query.is_empty());
+        assert_eq!(query.matches(""), None);
+        assert_eq!(query.matches("anything"), None);
+    }
+}
@@ -1,24 +1,36 @@
-use crate::config::AppConfig;
+use crate::color;
+use crate::config::{AppConfig, ATTRIBUTE_PREFIX, CompareConfig};
+use crate::geometry::{guarded, is_plausible, self_intersections};
+use crate::report::REPORT_FILE;
+use crate::search::{MatchKind, SearchQuery};
+use crate::stats::{self, Averages, DatasetStatistics};
+use crate::tiles::TileRenderer;
 use crate::types::SmallArea;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
 use axum::{
-    extract::{Query, State},
-    response::Json,
-    routing::get,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
     Router,
 };
 use geo::algorithm::contains::Contains;
-use geo::{Point, Rect};
-use rstar::{RTree, RTreeObject, AABB};
+use geo::{
+    Area, BooleanOps, BoundingRect, Centroid, Closest, ClosestPoint, HaversineDistance, Intersects, MultiPolygon, Point,
+    LineString, Polygon, Rect, SimplifyIdx,
+};
+use geojson::GeoJson;
+use rayon::prelude::*;
+use rstar::{PointDistance, RTree, RTreeObject, AABB};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tower_http::services::ServeDir;
 use tower_http::cors::CorsLayer;
 
 // Wrapper for RTree indexing
-struct AreaIndex {
+pub struct AreaIndex {
     index: usize,
     aabb: AABB<[f64; 2]>,
 }
@@ -30,29 +42,235 @@ impl RTreeObject for AreaIndex {
     }
 }
 
+// Distance to the bounding box: a lower bound on the distance to the area itself
+impl PointDistance for AreaIndex {
+    fn distance_2(&self, point: &[f64; 2]) -> f64 {
+        self.aabb.distance_2(point)
+    }
+}
+
+// Metres per degree of latitude
+const METRES_PER_DEGREE: f64 = 111_320.0;
+
 pub struct AppState {
     pub areas: Vec<SmallArea>,
     pub tree: RTree<AreaIndex>,
     pub config: AppConfig,
+    pub level_order: Vec<String>,
+    // Area id -> indices into `areas` (one per level using the id)
+    pub by_id: HashMap<String, Vec<usize>>,
+    // Set when server.dynamic_tiles is on
+    pub tiles: Option<TileRenderer>,
+    pub averages: Averages,
 }
 
 #[derive(Deserialize)]
 pub struct QueryParams {
     lat: f64,
     lon: f64,
+    // Restrict the answer to one geography level
+    level: Option<String>,
+    // Metres: at levels where no area contains the point, return the nearest one this close
+    max_distance: Option<f64>,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize)]
+pub struct BatchParams {
+    level: Option<String>,
+    max_distance: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchRequest {
+    Points(Vec<BatchPoint>),
+    Wrapped { points: Vec<BatchPoint> },
+}
+
+#[derive(Deserialize)]
+struct BatchPoint {
+    lat: f64,
+    lon: f64,
+    // Caller's reference for the point, echoed back
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+}
+
+// Largest batch /api/query/batch accepts, and the request body size that allows for
+const MAX_BATCH_POINTS: usize = 100_000;
+const MAX_BATCH_BODY: usize = 32 * 1024 * 1024;
+
+#[derive(Deserialize)]
+pub struct TileParams {
+    // Comma-separated categories to draw, e.g. "Asian,Black"; all when absent
+    categories: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AreaParams {
+    level: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SearchParams {
+    q: String,
+    level: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct AggregateParams {
+    // "min_lon,min_lat,max_lon,max_lat"; GET only, POST takes a GeoJSON polygon body instead
+    bbox: Option<String>,
+    // Geography level to sum over; defaults to the finest
+    level: Option<String>,
+    // Count partly covered areas by the share of their area inside the region
+    #[serde(default)]
+    apportion: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    bbox: String, // min_lon,min_lat,max_lon,max_lat
+    // Only this dataset's data (and only areas that have it); all datasets when absent
+    dataset: Option<String>,
+    // Geography level to export; defaults to the finest
+    level: Option<String>,
+    // Simplification tolerance in metres (approximate); 0 or absent keeps full detail
+    tolerance: Option<f64>,
+}
+
+// Most areas one /api/areas.geojson request returns
+const MAX_EXPORT_AREAS: usize = 20_000;
+
+// How well an area matched a search: kind, edit distance, and whether only an attribute
+// matched (ranked after ids and names)
+type SearchRank = (MatchKind, usize, bool);
+
+// Default and maximum number of /api/search results
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+const MAX_SEARCH_LIMIT: usize = 100;
+
+#[derive(Serialize, Clone)]
 pub struct QueryResponse {
     id: String,
+    level: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    // Display label: the name and attribute values, e.g. "Ballymun C, Dublin City"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<String, String>,
     population_data: HashMap<String, HashMap<String, u32>>,
+    // Totals, percentages, dot counts and averages per dataset
+    statistics: HashMap<String, DatasetStatistics>,
+    // Datasets compared with earlier censuses, keyed by dataset name then previous year
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    comparison: HashMap<String, BTreeMap<String, Comparison>>,
+    // Metres from the point, when the area was found by the nearest-area fallback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<f64>,
+}
+
+/// `/api/query`: the finest area containing the point, as before levels existed, plus the
+/// answer at every level.
+#[derive(Serialize)]
+pub struct PointResponse {
+    #[serde(flatten)]
+    area: QueryResponse,
+    levels: Vec<QueryResponse>, // Finest first, including the area above
+}
+
+/// The areas containing one point of a batch query.
+#[derive(Serialize)]
+pub struct BatchResult {
+    index: usize, // Position in the request
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    lat: f64,
+    lon: f64,
+    areas: Vec<QueryResponse>,
+}
+
+/// One area looked up by id, with where it is so a client can zoom to it.
+#[derive(Serialize)]
+pub struct AreaResponse {
+    #[serde(flatten)]
+    area: QueryResponse,
+    centroid: [f64; 2],   // lon, lat
+    bbox: [f64; 4],       // min lon, min lat, max lon, max lat
+}
+
+#[derive(Serialize)]
+pub struct SearchResult {
+    id: String,
+    level: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label: Option<String>,
+    matched: MatchKind,
+    centroid: [f64; 2],
+    bbox: [f64; 4],
+}
+
+/// Population totals for a region.
+#[derive(Serialize)]
+pub struct AggregateResponse {
+    level: String,
+    apportioned: bool,
+    // Areas counted, and how many of them only in part
+    areas: usize,
+    partial: usize,
+    population_data: HashMap<String, HashMap<String, f64>>,
+}
+
+/// What the frontend needs to build its layers and legend.
+#[derive(Serialize)]
+pub struct MetadataResponse {
+    layers: Vec<LayerMetadata>,
+    levels: Vec<String>, // Finest first
+    min_zoom: u8,
+    // Highest zoom tiles exist for: output.max_zoom, or server.dynamic_max_zoom with dynamic tiles
+    max_zoom: u8,
+    dynamic_tiles: bool,
+    // min lon, min lat, max lon, max lat of all areas
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bounds: Option<[f64; 4]>,
+    // Unix seconds, from the last `generate` run's report
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct LayerMetadata {
+    name: String,
+    dataset: String,
+    tile_url: String,
+    categories: Vec<CategoryMetadata>,
+}
+
+#[derive(Serialize)]
+pub struct CategoryMetadata {
+    name: String,
+    color: String, // #rrggbb or #rrggbbaa
+}
+
+/// Both census years side by side, with the change per category.
+#[derive(Serialize, Clone)]
+pub struct Comparison {
+    year: String,
+    previous_year: String,
+    current: HashMap<String, u32>,
+    previous: HashMap<String, u32>,
+    change: HashMap<String, i64>,
 }
 
-pub async fn start_server(config: AppConfig, areas: Vec<SmallArea>) -> Result<()> {
+pub async fn start_server(config: AppConfig, areas: Vec<SmallArea>, tiles: Option<TileRenderer>) -> Result<()> {
     // Build Spatial Index
     println!("Building spatial index for API...");
     let tree_items: Vec<AreaIndex> = areas.iter().enumerate().map(|(i, area)| {
-        use geo::bounding_rect::BoundingRect;
         let rect = area.geometry.bounding_rect().unwrap_or(
             Rect::new(
                 geo::Coord { x: 0.0, y: 0.0 }, 
@@ -68,10 +286,20 @@ pub async fn start_server(config: AppConfig, areas: Vec<SmallArea>) -> Result<()
     let tree = RTree::bulk_load(tree_items);
     println!("Spatial index built.");
 
+    let level_order = config.geography_levels().into_iter().map(|l| l.name).collect();
+    let mut by_id: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, area) in areas.iter().enumerate() {
+        by_id.entry(area.id.clone()).or_default().push(i);
+    }
+    let averages = Averages::new(&config, &areas);
     let state = Arc::new(AppState {
         areas,
         tree,
         config: config.clone(),
+        level_order,
+        by_id,
+        tiles,
+        averages,
     });
 
     let port = config.server.port;
@@ -79,11 +307,20 @@ pub async fn start_server(config: AppConfig, areas: Vec<SmallArea>) -> Result<()
     
     println!("Starting server on http://{}", addr);
 
-    let tile_service = ServeDir::new(&config.output.tile_dir);
-    
     let app = Router::new()
+        .route("/api/metadata", get(metadata_handler))
         .route("/api/query", get(query_handler))
-        .nest_service("/tiles", tile_service)
+        .route("/api/query/batch", post(batch_query_handler).layer(DefaultBodyLimit::max(MAX_BATCH_BODY)))
+        .route("/api/area/:id", get(area_handler))
+        .route("/api/search", get(search_handler))
+        .route("/api/areas.geojson", get(export_handler))
+        .route("/api/aggregate", get(aggregate_handler).post(aggregate_polygon_handler));
+    let app = if state.tiles.is_some() {
+        app.route("/tiles/:layer/:z/:x/:y", get(tile_handler))
+    } else {
+        app.nest_service("/tiles", ServeDir::new(&config.output.tile_dir))
+    };
+    let app = app
         .nest_service("/", ServeDir::new("."))
         .layer(CorsLayer::permissive())
         .with_state(state);
@@ -93,26 +330,698 @@ pub async fn start_server(config: AppConfig, areas: Vec<SmallArea>) -> Result<()
     Ok(())
 }
 
+/// Tile layers with their categories and colours, zoom range, bounds and generation time.
+async fn metadata_handler(State(state): State<Arc<AppState>>) -> Json<MetadataResponse> {
+    let config = &state.config;
+    let layers = config.tile_layers().into_iter().map(|layer| LayerMetadata {
+        tile_url: format!("/tiles/{}/{{z}}/{{x}}/{{y}}.png", layer.name),
+        categories: layer.categories.into_iter().map(|c| CategoryMetadata {
+            // Colours were checked when the config loaded
+            color: color::parse_color(&c.color).map(color::to_hex).unwrap_or(c.color),
+            name: c.name,
+        }).collect(),
+        name: layer.name,
+        dataset: layer.dataset,
+    }).collect();
+
+    let envelope = state.tree.root().envelope();
+    let (lower, upper) = (envelope.lower(), envelope.upper());
+    let bounds = Some([lower[0], lower[1], upper[0], upper[1]]).filter(|b| b.iter().all(|v| v.is_finite()));
+
+    // Read on every request so a regeneration shows up without a restart
+    let report_path = config.output.tile_dir.join(REPORT_FILE);
+    let generated_at = tokio::fs::read(&report_path).await.ok()
+        .and_then(|bytes| serde_json::from_slice::<serde_json::Value>(&bytes).ok())
+        .and_then(|report| report.get("generated_at")?.as_u64());
+
+    let dynamic_tiles = state.tiles.is_some();
+    Json(MetadataResponse {
+        layers,
+        levels: state.level_order.clone(),
+        min_zoom: config.output.min_zoom,
+        max_zoom: if dynamic_tiles { config.server.dynamic_max_zoom.max(config.output.max_zoom) } else { config.output.max_zoom },
+        dynamic_tiles,
+        bounds,
+        generated_at,
+    })
+}
+
+/// Returns the finest area containing the point, or `null`, with the area at every geography
+/// level in `levels`. With `max_distance`, levels with no such area fall back to the nearest
+/// one within range.
 async fn query_handler(
     State(state): State<Arc<AppState>>,
     Query(params): Query<QueryParams>,
-) -> Json<Option<QueryResponse>> {
+) -> Json<Option<PointResponse>> {
     let point = Point::new(params.lon, params.lat);
-    let envelope = AABB::from_point([params.lon, params.lat]);
+    let levels = query_point(&state, point, params.level.as_deref(), params.max_distance);
+    Json(levels.first().cloned().map(|area| PointResponse { area, levels }))
+}
+
+/// `/api/query` for many points at once, answered in parallel. Takes a JSON array of
+/// `{"lat", "lon", "id"}` objects (`id` optional and echoed back), the same wrapped as
+/// `{"points": [...]}`, or GeoJSON Point / MultiPoint geometries, Features or a
+/// FeatureCollection (feature ids are echoed). Results come back in input order.
+async fn batch_query_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<BatchParams>,
+    body: String,
+) -> Result<Json<Vec<BatchResult>>, (StatusCode, String)> {
+    let points = parse_batch(&body).map_err(bad_request)?;
+    if points.len() > MAX_BATCH_POINTS {
+        return Err(bad_request(anyhow!("At most {} points per batch (got {})", MAX_BATCH_POINTS, points.len())));
+    }
+
+    let results = tokio::task::spawn_blocking(move || {
+        points.into_par_iter().enumerate().map(|(index, point)| BatchResult {
+            index,
+            areas: query_point(&state, Point::new(point.lon, point.lat), params.level.as_deref(), params.max_distance),
+            id: point.id,
+            lat: point.lat,
+            lon: point.lon,
+        }).collect()
+    }).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(results))
+}
+
+fn query_point(state: &AppState, point: Point<f64>, level: Option<&str>, max_distance: Option<f64>) -> Vec<QueryResponse> {
+    let envelope = AABB::from_point([point.x(), point.y()]);
 
     // Query RTree
     let candidates = state.tree.locate_in_envelope_intersecting(&envelope);
 
-    for candidate in candidates {
-        if let Some(area) = state.areas.get(candidate.index) {
-             if area.geometry.contains(&point) {
-                 return Json(Some(QueryResponse {
-                     id: area.id.clone(),
-                     population_data: area.population_data.clone(),
-                 }));
-             }
+    let mut matches: Vec<&SmallArea> = candidates
+        .filter_map(|candidate| state.areas.get(candidate.index))
+        .filter(|area| level.is_none_or(|level| area.level == level))
+        .filter(|area| area.geometry.contains(&point))
+        .collect();
+    matches.sort_by_key(|area| state.level_order.iter().position(|l| l == &area.level));
+    let mut responses: Vec<QueryResponse> = matches.iter().map(|area| query_response(state, area)).collect();
+
+    if let Some(max_distance) = max_distance.filter(|d| *d > 0.0) {
+        for (position, level_name) in state.level_order.iter().enumerate() {
+            if level.is_some_and(|l| l != level_name) || matches.iter().any(|a| &a.level == level_name) {
+                continue;
+            }
+            if let Some((area, distance)) = nearest_area(state, point, level_name, max_distance) {
+                let mut response = query_response(state, area);
+                response.distance = Some((distance * 10.0).round() / 10.0);
+                // Keep finest-first order
+                let at = responses.iter()
+                    .position(|r| state.level_order.iter().position(|l| l == &r.level) > Some(position))
+                    .unwrap_or(responses.len());
+                responses.insert(at, response);
+            }
+        }
+    }
+
+    responses
+}
+
+fn parse_batch(body: &str) -> Result<Vec<BatchPoint>> {
+    let value: serde_json::Value = serde_json::from_str(body).map_err(|e| anyhow!("Invalid JSON: {}", e))?;
+    if value.get("type").is_none() {
+        // serde would also read a [lon, lat] pair into the struct, silently as lat, lon
+        let items = value.as_array().or_else(|| value.get("points").and_then(|p| p.as_array()));
+        if items.is_some_and(|items| items.iter().any(|p| !p.is_object())) {
+            return Err(anyhow!("Points must be {{\"lat\", \"lon\"}} objects, not coordinate arrays"));
+        }
+        let request: BatchRequest = serde_json::from_value(value)
+            .map_err(|e| anyhow!("Expected an array of {{\"lat\", \"lon\"}} points or GeoJSON: {}", e))?;
+        return Ok(match request {
+            BatchRequest::Points(points) | BatchRequest::Wrapped { points } => points,
+        });
+    }
+
+    let geojson = GeoJson::from_json_value(value).map_err(|e| anyhow!("Invalid GeoJSON: {}", e))?;
+    let features = match geojson {
+        GeoJson::Geometry(geometry) => vec![(None, geometry)],
+        GeoJson::Feature(feature) => feature_point(feature).into_iter().collect(),
+        GeoJson::FeatureCollection(fc) => fc.features.into_iter().filter_map(feature_point).collect(),
+    };
+
+    let mut points = Vec::new();
+    for (id, geometry) in features {
+        let positions = match geometry.value {
+            geojson::Value::Point(position) => vec![position],
+            geojson::Value::MultiPoint(positions) => positions,
+            _ => return Err(anyhow!("Only Point and MultiPoint geometries can be queried")),
+        };
+        for position in positions {
+            if position.len() < 2 {
+                return Err(anyhow!("Point positions need a longitude and a latitude"));
+            }
+            points.push(BatchPoint { lon: position[0], lat: position[1], id: id.clone() });
+        }
+    }
+    Ok(points)
+}
+
+/// A feature's id (as JSON) and geometry; features without geometry are skipped.
+fn feature_point(feature: geojson::Feature) -> Option<(Option<serde_json::Value>, geojson::Geometry)> {
+    let id = feature.id.map(|id| match id {
+        geojson::feature::Id::String(s) => serde_json::Value::String(s),
+        geojson::feature::Id::Number(n) => serde_json::Value::Number(n),
+    });
+    Some((id, feature.geometry?))
+}
+
+/// The area of `level` whose boundary is nearest the point, if within `max_distance` metres.
+/// Walks R-tree candidates in order of bounding-box distance and stops once no closer area
+/// is possible.
+fn nearest_area<'a>(state: &'a AppState, point: Point<f64>, level: &str, max_distance: f64) -> Option<(&'a SmallArea, f64)> {
+    // Bounding-box distances are in degrees; a degree is at least this many metres anywhere
+    // within `max_distance` of the point (longitude degrees shrink towards the poles, so take
+    // the highest latitude reachable, plus a degree of margin)
+    let reach = max_distance / METRES_PER_DEGREE;
+    let metres_per_degree = METRES_PER_DEGREE * (point.y().abs() + reach + 1.0).min(89.0).to_radians().cos();
+
+    let mut best: Option<(&SmallArea, f64)> = None;
+    for (candidate, distance_2) in state.tree.nearest_neighbor_iter_with_distance_2(&[point.x(), point.y()]) {
+        let lower_bound = distance_2.sqrt() * metres_per_degree;
+        if lower_bound > best.map_or(max_distance, |(_, d)| d) {
+            break;
+        }
+        let area = &state.areas[candidate.index];
+        if area.level != level {
+            continue;
+        }
+        let closest = match area.geometry.closest_point(&point) {
+            Closest::Intersection(p) | Closest::SinglePoint(p) => p,
+            Closest::Indeterminate => continue,
+        };
+        let distance = point.haversine_distance(&closest);
+        if distance <= best.map_or(max_distance, |(_, d)| d) {
+            best = Some((area, distance));
         }
     }
+    best
+}
 
-    Json(None)
+/// Looks an area up by id. Ids shared between levels resolve to the finest level unless
+/// `level` is given.
+async fn area_handler(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(params): Query<AreaParams>,
+) -> Result<Json<AreaResponse>, StatusCode> {
+    let area = state.by_id.get(&id)
+        .into_iter()
+        .flatten()
+        .map(|&i| &state.areas[i])
+        .filter(|area| params.level.as_ref().is_none_or(|level| &area.level == level))
+        .min_by_key(|area| state.level_order.iter().position(|l| l == &area.level))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let (centroid, bbox) = extent(area);
+    Ok(Json(AreaResponse { area: query_response(&state, area), centroid, bbox }))
+}
+
+/// Finds areas by id, name or attribute value: exact matches first, then prefix, substring
+/// and fuzzy (closest first). Ties go to id and name matches, then finer levels.
+async fn search_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<SearchParams>,
+) -> Json<Vec<SearchResult>> {
+    let query = SearchQuery::new(&params.q);
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+    if query.is_empty() {
+        return Json(Vec::new());
+    }
+
+    let mut hits: Vec<(SearchRank, Option<usize>, &SmallArea)> = state.areas.iter()
+        .filter(|area| params.level.as_ref().is_none_or(|level| &area.level == level))
+        .filter_map(|area| {
+            let own = std::iter::once(&area.id).chain(area.name.as_ref()).map(|text| (text, false));
+            let attributes = area.attributes.iter().map(|(_, value)| (value, true));
+            let best = own.chain(attributes)
+                .filter_map(|(text, is_attribute)| query.matches(text).map(|(kind, distance)| (kind, distance, is_attribute)))
+                .min()?;
+            Some((best, state.level_order.iter().position(|l| l == &area.level), area))
+        })
+        .collect();
+    hits.sort_by(|a, b| (a.0, a.1, &a.2.id).cmp(&(b.0, b.1, &b.2.id)));
+
+    Json(hits.into_iter().take(limit).map(|((matched, _, _), _, area)| {
+        let (centroid, bbox) = extent(area);
+        SearchResult {
+            id: area.id.clone(),
+            level: area.level.clone(),
+            name: area.name.clone(),
+            label: label(area),
+            matched,
+            centroid,
+            bbox,
+        }
+    }).collect())
+}
+
+/// Sums population data over the areas in `bbox`.
+async fn aggregate_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AggregateParams>,
+) -> Result<Json<AggregateResponse>, (StatusCode, String)> {
+    let bbox = params.bbox.as_deref().ok_or_else(|| {
+        bad_request(anyhow!("bbox=min_lon,min_lat,max_lon,max_lat is required (or POST a GeoJSON polygon)"))
+    })?;
+    let region = MultiPolygon::new(vec![parse_bbox(bbox).map_err(bad_request)?.to_polygon()]);
+    aggregate(state, region, params).await
+}
+
+/// Sums population data over the areas in a GeoJSON Polygon or MultiPolygon (bare, as a
+/// Feature, or a FeatureCollection of them).
+async fn aggregate_polygon_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<AggregateParams>,
+    body: String,
+) -> Result<Json<AggregateResponse>, (StatusCode, String)> {
+    // Unioning the input polygons can take a while
+    let region = tokio::task::spawn_blocking(move || parse_region(&body))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(bad_request)?;
+    aggregate(state, region, params).await
+}
+
+async fn aggregate(
+    state: Arc<AppState>,
+    region: MultiPolygon<f64>,
+    params: AggregateParams,
+) -> Result<Json<AggregateResponse>, (StatusCode, String)> {
+    let level = match params.level {
+        Some(level) if state.level_order.contains(&level) => level,
+        Some(level) => return Err(bad_request(anyhow!("Unknown geography level '{}'", level))),
+        None => state.level_order[0].clone(),
+    };
+    let response = tokio::task::spawn_blocking(move || aggregate_region(&state, &region, level, params.apportion))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(Json(response))
+}
+
+/// Without apportionment an area counts in full when its centroid is inside the region, so
+/// adjoining regions never share an area. With it, each area counts by the fraction of its
+/// area inside the region.
+fn aggregate_region(state: &AppState, region: &MultiPolygon<f64>, level: String, apportion: bool) -> AggregateResponse {
+    let envelope = match region.bounding_rect() {
+        Some(r) => AABB::from_corners([r.min().x, r.min().y], [r.max().x, r.max().y]),
+        None => AABB::from_point([0.0, 0.0]),
+    };
+    let candidates: Vec<&SmallArea> = state.tree.locate_in_envelope_intersecting(&envelope)
+        .map(|candidate| &state.areas[candidate.index])
+        .filter(|area| area.level == level)
+        .collect();
+
+    let centroid_inside = |area: &SmallArea| area.geometry.centroid().is_some_and(|c| region.contains(&c));
+    let shares: Vec<(&SmallArea, f64)> = candidates.into_par_iter()
+        .filter_map(|area| {
+            let total = area.geometry.unsigned_area();
+            let share = if apportion && total > 0.0 {
+                guarded(|| region.intersection(&area.geometry))
+                    .filter(is_plausible)
+                    .map(|inside| (inside.unsigned_area() / total).min(1.0))
+                    // Boolean op failed: fall back to the centroid rule
+                    .unwrap_or_else(|| if centroid_inside(area) { 1.0 } else { 0.0 })
+            } else if centroid_inside(area) {
+                1.0
+            } else {
+                0.0
+            };
+            (share > 0.0).then_some((area, share))
+        })
+        .collect();
+
+    let mut population_data: HashMap<String, HashMap<String, f64>> = HashMap::new();
+    for (area, share) in &shares {
+        for (dataset, counts) in &area.population_data {
+            let totals = population_data.entry(dataset.clone()).or_default();
+            for (category, count) in counts {
+                *totals.entry(category.clone()).or_default() += *count as f64 * share;
+            }
+        }
+    }
+    for value in population_data.values_mut().flat_map(|totals| totals.values_mut()) {
+        *value = (*value * 100.0).round() / 100.0;
+    }
+
+    AggregateResponse {
+        level,
+        apportioned: apportion,
+        areas: shares.len(),
+        partial: shares.iter().filter(|(_, share)| *share < 1.0).count(),
+        population_data,
+    }
+}
+
+/// Areas intersecting `bbox` as a GeoJSON FeatureCollection, for GIS tools. Properties are
+/// the id, level, name and attributes, then counts as "<Dataset>.<Category>" (just
+/// "<Category>" when `dataset` is given).
+async fn export_handler(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, (StatusCode, String)> {
+    let bbox = parse_bbox(&params.bbox).map_err(bad_request)?;
+    let level = match params.level.clone() {
+        Some(level) if state.level_order.contains(&level) => level,
+        Some(level) => return Err(bad_request(anyhow!("Unknown geography level '{}'", level))),
+        None => state.level_order[0].clone(),
+    };
+    if let Some(dataset) = &params.dataset {
+        if !state.config.processing.datasets.contains_key(dataset) {
+            return Err(bad_request(anyhow!("Unknown dataset '{}'", dataset)));
+        }
+    }
+    let tolerance = params.tolerance.filter(|t| t.is_finite() && *t > 0.0);
+
+    let json = tokio::task::spawn_blocking(move || {
+        export_areas(&state, bbox, &level, params.dataset.as_deref(), tolerance)
+    }).await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?.map_err(bad_request)?;
+    Ok(([(header::CONTENT_TYPE, "application/geo+json")], json).into_response())
+}
+
+fn export_areas(state: &AppState, bbox: Rect<f64>, level: &str, dataset: Option<&str>, tolerance: Option<f64>) -> Result<String> {
+    let envelope = AABB::from_corners([bbox.min().x, bbox.min().y], [bbox.max().x, bbox.max().y]);
+    let mut areas: Vec<&SmallArea> = state.tree.locate_in_envelope_intersecting(&envelope)
+        .map(|candidate| &state.areas[candidate.index])
+        .filter(|area| area.level == level)
+        .filter(|area| dataset.is_none_or(|d| area.population_data.contains_key(d)))
+        .filter(|area| area.geometry.intersects(&bbox))
+        .collect();
+    if areas.len() > MAX_EXPORT_AREAS {
+        return Err(anyhow!("{} areas in the bbox; at most {} per request, use a smaller bbox", areas.len(), MAX_EXPORT_AREAS));
+    }
+    areas.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let features = areas.par_iter().map(|area| {
+        let geometry = match tolerance {
+            Some(metres) => simplify(&area.geometry, metres),
+            None => area.geometry.clone(),
+        };
+
+        let mut properties = geojson::JsonObject::new();
+        properties.insert("id".to_string(), area.id.clone().into());
+        properties.insert("level".to_string(), area.level.clone().into());
+        if let Some(name) = &area.name {
+            properties.insert("name".to_string(), name.clone().into());
+        }
+        // Namespaced so no attribute or count can shadow id, level or name
+        for (attribute, value) in &area.attributes {
+            properties.insert(format!("{}.{}", ATTRIBUTE_PREFIX, attribute), value.clone().into());
+        }
+        let mut datasets: Vec<(&String, &HashMap<String, u32>)> = area.population_data.iter()
+            .filter(|(name, _)| dataset.is_none_or(|d| d == name.as_str()))
+            .collect();
+        datasets.sort_by_key(|(name, _)| *name);
+        for (name, counts) in datasets {
+            let mut categories: Vec<(&String, &u32)> = counts.iter().collect();
+            categories.sort();
+            for (category, count) in categories {
+                properties.insert(format!("{}.{}", name, category), (*count).into());
+            }
+        }
+
+        geojson::Feature {
+            bbox: None,
+            geometry: Some(geojson::Geometry::from(&geometry)),
+            id: Some(geojson::feature::Id::String(area.id.clone())),
+            properties: Some(properties),
+            foreign_members: None,
+        }
+    }).collect();
+
+    let collection = geojson::FeatureCollection { bbox: None, features, foreign_members: None };
+    Ok(collection.to_string())
+}
+
+/// Douglas-Peucker simplification to `metres`, measured with longitude scaled by the cosine
+/// of the area's latitude. Kept vertices are the original ones. It never breaks a polygon:
+/// rings simplified below four points are dropped (holes) or left as they were (exteriors),
+/// and polygons whose simplified rings cross keep their full detail. Neighbouring areas are
+/// simplified independently, so shared borders can open small gaps or overlaps.
+fn simplify(geometry: &MultiPolygon<f64>, metres: f64) -> MultiPolygon<f64> {
+    let Some(bbox) = geometry.bounding_rect() else {
+        return geometry.clone();
+    };
+    let x_scale = METRES_PER_DEGREE * bbox.center().y.to_radians().cos();
+    let simplify_ring = |ring: &LineString<f64>| -> LineString<f64> {
+        let projected: LineString<f64> = ring.0.iter()
+            .map(|c| geo::Coord { x: c.x * x_scale, y: c.y * METRES_PER_DEGREE })
+            .collect();
+        projected.simplify_idx(&metres).into_iter().map(|i| ring.0[i]).collect()
+    };
+
+    let simplified: MultiPolygon<f64> = geometry.iter().map(|polygon| {
+        let exterior = simplify_ring(polygon.exterior());
+        let exterior = if exterior.0.len() >= 4 { exterior } else { polygon.exterior().clone() };
+        let interiors = polygon.interiors().iter().map(simplify_ring).filter(|ring| ring.0.len() >= 4).collect();
+        let simplified = MultiPolygon::new(vec![Polygon::new(exterior, interiors)]);
+        if self_intersections(&simplified) == 0 { simplified.0[0].clone() } else { polygon.clone() }
+    }).collect();
+
+    // Parts that were fine alone can still cross each other
+    if self_intersections(&simplified) == 0 { simplified } else { geometry.clone() }
+}
+
+fn parse_bbox(bbox: &str) -> Result<Rect<f64>> {
+    let values = bbox.split(',').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| anyhow!("bbox must be four numbers: min_lon,min_lat,max_lon,max_lat"))?;
+    match values[..] {
+        [min_x, min_y, max_x, max_y] if values.iter().all(|v| v.is_finite()) && min_x < max_x && min_y < max_y => {
+            Ok(Rect::new(geo::Coord { x: min_x, y: min_y }, geo::Coord { x: max_x, y: max_y }))
+        }
+        _ => Err(anyhow!("bbox must be four numbers: min_lon,min_lat,max_lon,max_lat, with min < max")),
+    }
+}
+
+/// Polygons from a GeoJSON geometry, Feature or FeatureCollection, in WGS84, unioned so
+/// overlapping input polygons count their common ground once.
+fn parse_region(body: &str) -> Result<MultiPolygon<f64>> {
+    let geojson: GeoJson = body.parse().map_err(|e| anyhow!("Invalid GeoJSON: {}", e))?;
+    let geometries = match geojson {
+        GeoJson::Geometry(geometry) => vec![geometry],
+        GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+        GeoJson::FeatureCollection(fc) => fc.features.into_iter().filter_map(|f| f.geometry).collect(),
+    };
+
+    let mut polygons = Vec::new();
+    for geometry in geometries {
+        let geometry: geo::Geometry<f64> = geometry.value.try_into()
+            .map_err(|e| anyhow!("Failed to convert GeoJSON geometry: {:?}", e))?;
+        match geometry {
+            geo::Geometry::Polygon(p) => polygons.push(p),
+            geo::Geometry::MultiPolygon(mp) => polygons.extend(mp),
+            _ => return Err(anyhow!("Only Polygon and MultiPolygon geometries can be aggregated over")),
+        }
+    }
+    if polygons.is_empty() {
+        return Err(anyhow!("No polygon in the GeoJSON body"));
+    }
+    let region = guarded(|| {
+        polygons.into_iter().fold(MultiPolygon::new(vec![]), |region, polygon| {
+            region.union(&MultiPolygon::new(vec![polygon]))
+        })
+    });
+    match region {
+        Some(region) if is_plausible(&region) && !region.0.is_empty() => Ok(region),
+        _ => Err(anyhow!("The GeoJSON polygons could not be combined into one region; check they are valid")),
+    }
+}
+
+fn bad_request(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, e.to_string())
+}
+
+fn query_response(state: &AppState, area: &SmallArea) -> QueryResponse {
+    let config = &state.config;
+    QueryResponse {
+        id: area.id.clone(),
+        level: area.level.clone(),
+        name: area.name.clone(),
+        label: label(area),
+        attributes: area.attributes.iter().cloned().collect(),
+        population_data: area.population_data.clone(),
+        statistics: stats::area_statistics(config, &state.averages, area),
+        comparison: comparisons(config, area),
+        distance: None,
+    }
+}
+
+/// The area's name followed by its attribute values, in config order.
+fn label(area: &SmallArea) -> Option<String> {
+    let parts: Vec<&str> = area.name.iter()
+        .chain(area.attributes.iter().map(|(_, value)| value))
+        .map(String::as_str)
+        .collect();
+    (!parts.is_empty()).then(|| parts.join(", "))
+}
+
+/// Centroid and bounding box of an area, in lon/lat.
+fn extent(area: &SmallArea) -> ([f64; 2], [f64; 4]) {
+    let bbox = area.geometry.bounding_rect()
+        .map(|r| [r.min().x, r.min().y, r.max().x, r.max().y])
+        .unwrap_or_default();
+    let centroid = area.geometry.centroid()
+        .map(|p| [p.x(), p.y()])
+        .unwrap_or([(bbox[0] + bbox[2]) / 2.0, (bbox[1] + bbox[3]) / 2.0]);
+    (centroid, bbox)
+}
+
+/// Renders a tile from the dots, or serves it from the renderer's caches. `tile_dir` is
+/// bypassed so styling changes show at every zoom without regenerating.
+async fn tile_handler(
+    State(state): State<Arc<AppState>>,
+    Path((layer, z, x, y)): Path<(String, u8, u32, String)>,
+    Query(params): Query<TileParams>,
+) -> Response {
+    let (Some(tiles), Some(Ok(y))) = (&state.tiles, y.strip_suffix(".png").map(str::parse::<u32>)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    // Only known layer names touch the filesystem
+    if !tiles.has_layer(&layer) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+
+    let filter = match tiles.category_filter(&layer, params.categories.as_deref().unwrap_or("")) {
+        Ok(filter) => filter,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let rendered = tokio::task::spawn_blocking({
+        let state = state.clone();
+        move || state.tiles.as_ref().and_then(|tiles| tiles.tile(&layer, z, x, y, &filter))
+    }).await;
+    match rendered {
+        Ok(Some(png)) => ([(header::CONTENT_TYPE, "image/png")], png.as_ref().clone()).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+fn comparisons(config: &AppConfig, area: &SmallArea) -> HashMap<String, BTreeMap<String, Comparison>> {
+    let mut result: HashMap<String, BTreeMap<String, Comparison>> = HashMap::new();
+    for (dataset_name, vintages) in &area.previous_data {
+        let Some(dataset_config) = config.processing.datasets.get(dataset_name) else { continue };
+        for compare in &dataset_config.compare {
+            let Some(previous) = vintages.get(&compare.previous_year) else { continue };
+            let comparison = compare_counts(compare, area.population_data.get(dataset_name), previous);
+            result.entry(dataset_name.clone()).or_default().insert(compare.previous_year.clone(), comparison);
+        }
+    }
+    result
+}
+
+fn compare_counts(compare: &CompareConfig, current: Option<&HashMap<String, u32>>, previous: &HashMap<String, u32>) -> Comparison {
+    let current = current.cloned().unwrap_or_default();
+    let categories: HashSet<&String> = current.keys().chain(previous.keys()).collect();
+    let change = categories.into_iter()
+        .map(|c| {
+            let now = *current.get(c).unwrap_or(&0) as i64;
+            let then = *previous.get(c).unwrap_or(&0) as i64;
+            (c.clone(), now - then)
+        })
+        .collect();
+    Comparison {
+        year: compare.year.clone(),
+        previous_year: compare.previous_year.clone(),
+        current,
+        previous: previous.clone(),
+        change,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn points(body: &str) -> Vec<(f64, f64, Option<serde_json::Value>)> {
+        parse_batch(body).unwrap().into_iter().map(|p| (p.lon, p.lat, p.id)).collect()
+    }
+
+    #[test]
+    fn batch_accepts_plain_and_wrapped_arrays() {
+        let plain = r#"[{"lat": 53.35, "lon": -6.26, "id": "r1"}, {"lat": 51.9, "lon": -8.47}]"#;
+        let expected = vec![(-6.26, 53.35, Some(json!("r1"))), (-8.47, 51.9, None)];
+        assert_eq!(points(plain), expected);
+        assert_eq!(points(&format!(r#"{{"points": {}}}"#, plain)), expected);
+        assert!(points("[]").is_empty());
+    }
+
+    #[test]
+    fn batch_accepts_geojson() {
+        assert_eq!(points(r#"{"type": "Point", "coordinates": [-6.26, 53.35]}"#), vec![(-6.26, 53.35, None)]);
+        let collection = r#"{"type": "FeatureCollection", "features": [
+            {"type": "Feature", "id": 7, "properties": null,
+             "geometry": {"type": "MultiPoint", "coordinates": [[-6.26, 53.35], [-8.47, 51.9]]}},
+            {"type": "Feature", "properties": null, "geometry": null},
+            {"type": "Feature", "id": "x", "properties": {},
+             "geometry": {"type": "Point", "coordinates": [-9.05, 53.27, 12.0]}}
+        ]}"#;
+        // Features without geometry are skipped; every point of a MultiPoint keeps the feature id
+        assert_eq!(points(collection), vec![
+            (-6.26, 53.35, Some(json!(7))),
+            (-8.47, 51.9, Some(json!(7))),
+            (-9.05, 53.27, Some(json!("x"))),
+        ]);
+    }
+
+    #[test]
+    fn batch_rejects_malformed_bodies() {
+        for body in [
+            "",
+            "[{\"lat\": 53.35}]",
+            "{\"pts\": []}",
+            "[[-6.26, 53.35]]",
+            r#"{"type": "LineString", "coordinates": [[-6.26, 53.35], [-6.2, 53.3]]}"#,
+            r#"{"type": "Point", "coordinates": [-6.26]}"#,
+        ] {
+            assert!(parse_batch(body).is_err(), "{:?} parsed", body);
+        }
+    }
+
+    fn square_with_bulge(holes: Vec<LineString<f64>>) -> MultiPolygon<f64> {
+        // A 0.2° (22 km) bulge on top of a 1° square, on the equator
+        let exterior = LineString::from(vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.5, 1.2), (0.0, 1.0), (0.0, 0.0)]);
+        MultiPolygon::new(vec![Polygon::new(exterior, holes)])
+    }
+
+    #[test]
+    fn simplify_drops_small_detail() {
+        let simplified = simplify(&square_with_bulge(vec![]), 25_000.0);
+        assert_eq!(simplified.0[0].exterior().0.len(), 5);
+        assert_eq!(simplify(&square_with_bulge(vec![]), 10_000.0), square_with_bulge(vec![]));
+        assert!(simplify(&MultiPolygon::new(vec![]), 25_000.0).0.is_empty());
+    }
+
+    #[test]
+    fn simplify_keeps_full_detail_when_rings_would_cross() {
+        // The hole reaches into the bulge, so flattening the bulge would cut through it
+        let hole = LineString::from(vec![(0.35, 0.5), (0.65, 0.5), (0.65, 1.05), (0.35, 1.05), (0.35, 0.5)]);
+        let geometry = square_with_bulge(vec![hole]);
+        assert_eq!(self_intersections(&geometry), 0);
+        assert_eq!(simplify(&geometry, 25_000.0), geometry);
+    }
+
+    #[test]
+    fn parses_bbox() {
+        let bbox = parse_bbox(" -6.4, 53.2,-6.1 ,53.4").unwrap();
+        assert_eq!((bbox.min().x, bbox.min().y, bbox.max().x, bbox.max().y), (-6.4, 53.2, -6.1, 53.4));
+    }
+
+    #[test]
+    fn rejects_malformed_bbox() {
+        for bbox in [
+            "",
+            "-6.4,53.2,-6.1",
+            "-6.4,53.2,-6.1,53.4,0",
+            "-6.4,53.2,-6.1,north",
+            "-6.4,53.2,NaN,53.4",
+            "-6.4,53.2,inf,53.4",
+            "-6.1,53.2,-6.4,53.4",
+            "-6.4,53.4,-6.1,53.2",
+            "-6.4,53.2,-6.4,53.4",
+        ] {
+            assert!(parse_bbox(bbox).is_err(), "{:?} parsed", bbox);
+        }
+    }
 }
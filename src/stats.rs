@@ -0,0 +1,236 @@
+use crate::config::{AppConfig, DatasetConfig};
+use crate::processing;
+use crate::types::SmallArea;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Derived figures for one dataset in one area.
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetStatistics {
+    total: u64, // Categories plus Not Stated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_stated: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not_stated_share: Option<f64>, // % of total
+    // % of the stated population per category, which is also each category's share of the dots
+    percentages: HashMap<String, f64>,
+    // Counts drawn as dots, with Not Stated shared out in proportion
+    dots: HashMap<String, u32>,
+    // Percentages over every area of the same level
+    national: HashMap<String, f64>,
+    // Percentages over the areas of the same level in the same county
+    #[serde(skip_serializing_if = "Option::is_none")]
+    county: Option<CountyAverage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CountyAverage {
+    name: String,
+    percentages: HashMap<String, f64>,
+}
+
+/// Category totals per level and dataset, nationally and per county, summed once at startup.
+pub struct Averages {
+    // (level, dataset) -> category -> count
+    national: HashMap<(String, String), HashMap<String, u64>>,
+    // (level, county, dataset) -> category -> count
+    county: HashMap<(String, String, String), HashMap<String, u64>>,
+    county_attribute: Option<String>,
+}
+
+impl Averages {
+    pub fn new(config: &AppConfig, areas: &[SmallArea]) -> Self {
+        let county_attribute = config.server.county_attribute.clone();
+        let mut national: HashMap<(String, String), HashMap<String, u64>> = HashMap::new();
+        let mut county: HashMap<(String, String, String), HashMap<String, u64>> = HashMap::new();
+
+        for area in areas {
+            let county_name = county_attribute.as_ref().and_then(|attribute| attribute_value(area, attribute));
+            for (dataset, counts) in &area.population_data {
+                let Some(dataset_config) = config.processing.datasets.get(dataset) else {
+                    continue;
+                };
+                let add = |totals: &mut HashMap<String, u64>| {
+                    for category in &dataset_config.categories {
+                        *totals.entry(category.name.clone()).or_default() += *counts.get(&category.name).unwrap_or(&0) as u64;
+                    }
+                };
+                add(national.entry((area.level.clone(), dataset.clone())).or_default());
+                if let Some(name) = county_name {
+                    add(county.entry((area.level.clone(), name.to_string(), dataset.clone())).or_default());
+                }
+            }
+        }
+        Averages { national, county, county_attribute }
+    }
+}
+
+/// Statistics for every dataset the area has data for.
+pub fn area_statistics(config: &AppConfig, averages: &Averages, area: &SmallArea) -> HashMap<String, DatasetStatistics> {
+    let county_name = averages.county_attribute.as_ref().and_then(|attribute| attribute_value(area, attribute));
+    area.population_data.iter()
+        .filter_map(|(dataset, counts)| {
+            let dataset_config = config.processing.datasets.get(dataset)?;
+            let national = averages.national.get(&(area.level.clone(), dataset.clone()))
+                .map(|totals| percentages(dataset_config, |c| totals.get(c).copied().unwrap_or(0)))
+                .unwrap_or_default();
+            let county = county_name.and_then(|name| {
+                let totals = averages.county.get(&(area.level.clone(), name.to_string(), dataset.clone()))?;
+                Some(CountyAverage {
+                    name: name.to_string(),
+                    percentages: percentages(dataset_config, |c| totals.get(c).copied().unwrap_or(0)),
+                })
+            });
+            Some((dataset.clone(), dataset_statistics(dataset_config, counts, national, county)))
+        })
+        .collect()
+}
+
+fn dataset_statistics(
+    dataset_config: &DatasetConfig,
+    counts: &HashMap<String, u32>,
+    national: HashMap<String, f64>,
+    county: Option<CountyAverage>,
+) -> DatasetStatistics {
+    // u64: several categories near u32::MAX must not overflow the total
+    let stated: u64 = dataset_config.categories.iter().map(|c| *counts.get(&c.name).unwrap_or(&0) as u64).sum();
+    let not_stated = dataset_config.not_stated.as_ref().map(|_| *counts.get("Not Stated").unwrap_or(&0) as u64);
+    let total = stated + not_stated.unwrap_or(0);
+
+    DatasetStatistics {
+        total,
+        not_stated,
+        not_stated_share: not_stated.map(|n| percent(n, total)),
+        percentages: percentages(dataset_config, |c| *counts.get(c).unwrap_or(&0) as u64),
+        dots: processing::redistribute(dataset_config, counts).into_iter().collect(),
+        national,
+        county,
+    }
+}
+
+/// Each category's % of the dataset's stated categories.
+fn percentages(dataset_config: &DatasetConfig, count: impl Fn(&String) -> u64) -> HashMap<String, f64> {
+    let stated: u64 = dataset_config.categories.iter().map(|c| count(&c.name)).sum();
+    dataset_config.categories.iter()
+        .map(|c| (c.name.clone(), percent(count(&c.name), stated)))
+        .collect()
+}
+
+/// `part` as a percentage of `whole`, to two decimals; 0 when `whole` is 0.
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        return 0.0;
+    }
+    (part as f64 / whole as f64 * 10_000.0).round() / 100.0
+}
+
+fn attribute_value<'a>(area: &'a SmallArea, attribute: &str) -> Option<&'a str> {
+    area.attributes.iter().find(|(name, _)| name == attribute).map(|(_, value)| value.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r##"
+        [input]
+        shapefile = "sa.shp"
+        data_csv = "sa.csv"
+        join_column_shape = "SA_ID"
+        join_column_csv = "SA_ID"
+
+        [processing]
+        seed = 1
+
+        [processing.datasets.Ethnicity]
+        categories = [
+            { name = "White Irish", color = "#1f77b4", columns = ["WI"] },
+            { name = "Asian", color = "#ff7f0e", columns = ["AS"] },
+        ]
+        not_stated = { column = "NS" }
+
+        [output]
+        tile_dir = "tiles"
+        min_zoom = 10
+        max_zoom = 12
+
+        [server]
+        port = 3000
+        county_attribute = "COUNTY"
+    "##;
+
+    fn config() -> AppConfig {
+        toml::from_str(CONFIG).unwrap()
+    }
+
+    fn area(id: &str, county: &str, counts: &[(&str, u32)]) -> SmallArea {
+        let counts = counts.iter().map(|(name, count)| (name.to_string(), *count)).collect();
+        SmallArea {
+            id: id.to_string(),
+            level: "SmallArea".to_string(),
+            name: None,
+            attributes: vec![("COUNTY".to_string(), county.to_string())],
+            geometry: geo::MultiPolygon::new(vec![]),
+            population_data: HashMap::from([("Ethnicity".to_string(), counts)]),
+            previous_data: HashMap::new(),
+            masked: HashMap::new(),
+        }
+    }
+
+    fn statistics(areas: &[SmallArea], index: usize) -> DatasetStatistics {
+        let config = config();
+        let averages = Averages::new(&config, areas);
+        area_statistics(&config, &averages, &areas[index]).remove("Ethnicity").unwrap()
+    }
+
+    #[test]
+    fn percent_rounds_to_two_decimals() {
+        assert_eq!(percent(1, 3), 33.33);
+        assert_eq!(percent(2, 3), 66.67);
+        assert_eq!(percent(5, 5), 100.0);
+        assert_eq!(percent(0, 0), 0.0);
+        assert_eq!(percent(u64::MAX / 2, u64::MAX), 50.0);
+    }
+
+    #[test]
+    fn not_stated_counts_toward_total_only() {
+        let areas = [area("A", "Cork", &[("White Irish", 60), ("Asian", 20), ("Not Stated", 20)])];
+        let stats = statistics(&areas, 0);
+        assert_eq!(stats.total, 100);
+        assert_eq!(stats.not_stated, Some(20));
+        assert_eq!(stats.not_stated_share, Some(20.0));
+        // Percentages are of the stated population; the dots share Not Stated out in proportion
+        assert_eq!(stats.percentages["White Irish"], 75.0);
+        assert_eq!(stats.percentages["Asian"], 25.0);
+        assert_eq!(stats.dots["White Irish"], 75);
+        assert_eq!(stats.dots["Asian"], 25);
+    }
+
+    #[test]
+    fn totals_do_not_overflow_u32() {
+        let areas = [area("A", "Cork", &[("White Irish", u32::MAX), ("Asian", u32::MAX), ("Not Stated", u32::MAX)])];
+        let stats = statistics(&areas, 0);
+        assert_eq!(stats.total, 3 * u32::MAX as u64);
+        assert_eq!(stats.not_stated_share, Some(33.33));
+    }
+
+    #[test]
+    fn national_and_county_averages() {
+        let areas = [
+            area("A", "Cork", &[("White Irish", 90), ("Asian", 10)]),
+            area("B", "Cork", &[("White Irish", 70), ("Asian", 30)]),
+            area("C", "Dublin", &[("White Irish", 40), ("Asian", 60), ("Not Stated", 50)]),
+        ];
+        let stats = statistics(&areas, 0);
+        // Summed counts, not averaged percentages: 200 / 300 nationally, 160 / 200 in Cork
+        assert_eq!(stats.national["White Irish"], 66.67);
+        assert_eq!(stats.national["Asian"], 33.33);
+        let county = stats.county.unwrap();
+        assert_eq!(county.name, "Cork");
+        assert_eq!(county.percentages["White Irish"], 80.0);
+        assert_eq!(county.percentages["Asian"], 20.0);
+
+        let dublin = statistics(&areas, 2).county.unwrap();
+        assert_eq!(dublin.percentages["Asian"], 60.0);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::config::AppConfig;
+use crate::render::{self, TILE_SIZE};
+use crate::types::Dot;
+use anyhow::{anyhow, Context, Result};
+use image::{ImageBuffer, ImageOutputFormat, Rgba, RgbaImage};
+use rstar::{RTree, RTreeObject, AABB};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Renders tiles on request for `serve`, from dots held in a spatial index per tile layer.
+/// Rendered tiles are kept in an in-memory LRU and optionally on disk.
+pub struct TileRenderer {
+    layers: HashMap<String, LayerDots>,
+    min_zoom: u8,
+    max_zoom: u8,
+    memory: Mutex<TileLru>,
+    disk_dir: Option<PathBuf>,
+}
+
+struct LayerDots {
+    tree: RTree<IndexedDot>,
+    categories: Vec<String>,
+    colors: Vec<Rgba<u8>>,
+    // Hash of the dots, categories and colours; names the layer's disk cache directory
+    fingerprint: String,
+}
+
+struct IndexedDot {
+    point: [f64; 2],
+    // Position in the generated dot order, so overlaps are painted as `generate` paints them
+    order: u32,
+    color: u16,
+}
+
+impl RTreeObject for IndexedDot {
+    type Envelope = AABB<[f64; 2]>;
+    fn envelope(&self) -> Self::Envelope {
+        AABB::from_point(self.point)
+    }
+}
+
+/// Categories to draw, as sorted indices into the layer's categories. `None` draws them all.
+pub type CategoryFilter = Option<Vec<u16>>;
+
+type TileKey = (String, u8, u32, u32, CategoryFilter);
+
+impl TileRenderer {
+    pub fn new(config: &AppConfig, dots: Vec<Dot>) -> Result<Self> {
+        println!("Indexing {} dots for dynamic tiles...", dots.len());
+
+        let mut layers = HashMap::new();
+        for layer in config.tile_layers() {
+            let colors = render::layer_colors(&layer)?;
+            let palette: Vec<Rgba<u8>> = layer.categories.iter().map(|c| colors[&c.name]).collect();
+            let category_index: HashMap<&String, u16> = layer.categories.iter()
+                .enumerate()
+                .map(|(i, c)| (&c.name, i as u16))
+                .collect();
+
+            let mut hasher = Sha256::new();
+            for (category, color) in layer.categories.iter().zip(&palette) {
+                hasher.update((category.name.len() as u64).to_le_bytes());
+                hasher.update(category.name.as_bytes());
+                hasher.update(color.0);
+            }
+            let mut entries = Vec::new();
+            for (order, dot) in dots.iter().enumerate().filter(|(_, d)| d.dataset == layer.name) {
+                // Categories without a colour aren't drawn, as in `generate`
+                let Some(&color) = category_index.get(&dot.category) else {
+                    continue;
+                };
+                hasher.update(dot.point.x().to_le_bytes());
+                hasher.update(dot.point.y().to_le_bytes());
+                hasher.update(color.to_le_bytes());
+                entries.push(IndexedDot { point: [dot.point.x(), dot.point.y()], order: order as u32, color });
+            }
+            let fingerprint: String = hasher.finalize().iter().take(8).map(|b| format!("{:02x}", b)).collect();
+
+            layers.insert(layer.name.clone(), LayerDots {
+                tree: RTree::bulk_load(entries),
+                categories: layer.categories.iter().map(|c| c.name.clone()).collect(),
+                colors: palette,
+                fingerprint,
+            });
+        }
+        println!("Dynamic tiles enabled up to zoom {}.", config.server.dynamic_max_zoom);
+
+        Ok(TileRenderer {
+            layers,
+            min_zoom: config.output.min_zoom,
+            max_zoom: config.server.dynamic_max_zoom,
+            memory: Mutex::new(TileLru::new(config.server.tile_cache_size)),
+            disk_dir: config.server.tile_cache_dir.clone(),
+        })
+    }
+
+    pub fn has_layer(&self, layer: &str) -> bool {
+        self.layers.contains_key(layer)
+    }
+
+    /// Parses a comma-separated list of category names, e.g. `Asian,Black`. An empty list
+    /// or one naming every category means no filter.
+    pub fn category_filter(&self, layer: &str, categories: &str) -> Result<CategoryFilter> {
+        let dots = self.layers.get(layer).ok_or_else(|| anyhow!("Unknown tile layer '{}'", layer))?;
+        let mut indices = Vec::new();
+        for name in categories.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let index = dots.categories.iter().position(|c| c == name)
+                .ok_or_else(|| anyhow!("Unknown category '{}' in layer '{}' (expected one of: {})", name, layer, dots.categories.join(", ")))?;
+            indices.push(index as u16);
+        }
+        indices.sort_unstable();
+        indices.dedup();
+        if indices.is_empty() || indices.len() == dots.categories.len() {
+            return Ok(None);
+        }
+        Ok(Some(indices))
+    }
+
+    /// PNG bytes for a tile, or `None` for an unknown layer or out-of-range tile. Zooms below
+    /// `output.min_zoom` are refused, as each of their tiles would cover most of the dots.
+    pub fn tile(&self, layer: &str, zoom: u8, x: u32, y: u32, filter: &CategoryFilter) -> Option<Arc<Vec<u8>>> {
+        let dots = self.layers.get(layer)?;
+        let n = 1u64.checked_shl(zoom.into())?;
+        if zoom < self.min_zoom || zoom > self.max_zoom || x as u64 >= n || y as u64 >= n {
+            return None;
+        }
+
+        let key = (layer.to_string(), zoom, x, y, filter.clone());
+        if let Some(png) = self.memory.lock().unwrap().get(&key) {
+            return Some(png);
+        }
+
+        let disk_path = self.disk_dir.as_ref().map(|dir| {
+            let mut dir = dir.join(format!("{}-{}", layer, dots.fingerprint));
+            if let Some(indices) = filter {
+                let names: Vec<String> = indices.iter().map(|i| i.to_string()).collect();
+                dir = dir.join(format!("only-{}", names.join("-")));
+            }
+            dir.join(zoom.to_string())
+                .join(x.to_string())
+                .join(format!("{}.png", y))
+        });
+        let cached = disk_path.as_ref().and_then(|path| fs::read(path).ok());
+        let png = match cached {
+            Some(png) => Arc::new(png),
+            None => {
+                let png = match encode_png(&render_tile(dots, zoom, x, y, filter)) {
+                    Ok(png) => Arc::new(png),
+                    Err(e) => {
+                        eprintln!("Failed to encode tile {}/{}/{}/{}: {:#}", layer, zoom, x, y, e);
+                        return None;
+                    }
+                };
+                if let Some(path) = &disk_path {
+                    if let Err(e) = write_atomic(path, &png) {
+                        eprintln!("Failed to cache tile {:?}: {:#}", path, e);
+                    }
+                }
+                png
+            }
+        };
+
+        self.memory.lock().unwrap().insert(key, png.clone());
+        Some(png)
+    }
+}
+
+fn render_tile(dots: &LayerDots, zoom: u8, x: u32, y: u32, filter: &CategoryFilter) -> RgbaImage {
+    let (west, south, east, north) = render::tile_bounds(zoom, x, y);
+    // Slightly padded; dots on the edges are assigned to a tile exactly as `generate` does below
+    let pad = (east - west) * 1e-6;
+    let envelope = AABB::from_corners([west - pad, south - pad], [east + pad, north + pad]);
+
+    let mut hits: Vec<&IndexedDot> = dots.tree.locate_in_envelope(&envelope)
+        .filter(|dot| filter.as_ref().is_none_or(|indices| indices.binary_search(&dot.color).is_ok()))
+        .collect();
+    hits.sort_unstable_by_key(|dot| dot.order);
+
+    let mut img: RgbaImage = ImageBuffer::new(TILE_SIZE, TILE_SIZE);
+    for dot in hits {
+        let (tx, ty, px, py) = render::lat_lon_to_tile_pixel(dot.point[1], dot.point[0], zoom);
+        if (tx, ty) == (x, y) {
+            img.put_pixel(px, py, dots.colors[dot.color as usize]);
+        }
+    }
+    img
+}
+
+fn encode_png(img: &RgbaImage) -> Result<Vec<u8>> {
+    let mut bytes = Cursor::new(Vec::new());
+    img.write_to(&mut bytes, ImageOutputFormat::Png)?;
+    Ok(bytes.into_inner())
+}
+
+fn write_atomic(path: &PathBuf, bytes: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Failed to create tile cache directory")?;
+    }
+    // A name per writer, so concurrent renders of one tile never share a temp file
+    static WRITES: AtomicU64 = AtomicU64::new(0);
+    let tmp = path.with_extension(format!("{}-{}.tmp", std::process::id(), WRITES.fetch_add(1, Ordering::Relaxed)));
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Least-recently-used tile cache with a fixed number of entries.
+struct TileLru {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<TileKey, (Arc<Vec<u8>>, u64)>,
+    // Last use -> key, oldest first
+    recency: BTreeMap<u64, TileKey>,
+}
+
+impl TileLru {
+    fn new(capacity: usize) -> Self {
+        TileLru { capacity, tick: 0, entries: HashMap::new(), recency: BTreeMap::new() }
+    }
+
+    fn get(&mut self, key: &TileKey) -> Option<Arc<Vec<u8>>> {
+        self.tick += 1;
+        let (png, used) = self.entries.get_mut(key)?;
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, key.clone());
+        Some(png.clone())
+    }
+
+    fn insert(&mut self, key: TileKey, png: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        if let Some((_, used)) = self.entries.insert(key.clone(), (png, self.tick)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.tick, key);
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(x: u32) -> TileKey {
+        ("Ethnicity".to_string(), 10, x, 0, None)
+    }
+
+    fn png(byte: u8) -> Arc<Vec<u8>> {
+        Arc::new(vec![byte])
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut lru = TileLru::new(2);
+        lru.insert(key(1), png(1));
+        lru.insert(key(2), png(2));
+        // Reading 1 makes 2 the oldest
+        assert_eq!(lru.get(&key(1)).as_deref(), Some(&vec![1]));
+        lru.insert(key(3), png(3));
+        assert!(lru.get(&key(2)).is_none());
+        assert!(lru.get(&key(1)).is_some());
+        assert!(lru.get(&key(3)).is_some());
+        assert_eq!(lru.entries.len(), lru.recency.len());
+    }
+
+    #[test]
+    fn reinserting_refreshes_without_growing() {
+        let mut lru = TileLru::new(2);
+        lru.insert(key(1), png(1));
+        lru.insert(key(2), png(2));
+        lru.insert(key(1), png(9));
+        lru.insert(key(3), png(3));
+        assert_eq!(lru.get(&key(1)).as_deref(), Some(&vec![9]));
+        assert!(lru.get(&key(2)).is_none());
+        assert_eq!((lru.entries.len(), lru.recency.len()), (2, 2));
+    }
+
+    #[test]
+    fn filters_are_separate_entries() {
+        let mut lru = TileLru::new(4);
+        let filtered = ("Ethnicity".to_string(), 10, 1, 0, Some(vec![0, 2]));
+        lru.insert(key(1), png(1));
+        lru.insert(filtered.clone(), png(2));
+        assert_eq!(lru.get(&key(1)).as_deref(), Some(&vec![1]));
+        assert_eq!(lru.get(&filtered).as_deref(), Some(&vec![2]));
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let mut lru = TileLru::new(0);
+        lru.insert(key(1), png(1));
+        assert!(lru.get(&key(1)).is_none());
+    }
+}
@@ -3,9 +3,36 @@ use geo::{Point, MultiPolygon};
 #[derive(Debug, Clone)]
 pub struct SmallArea {
     pub id: String,
+    pub level: String, // Geography level, e.g. "SmallArea" or "ElectoralDivision"
+    pub name: Option<String>, // From the level's name_column, if configured
+    // The level's `attributes` present on the feature, in config order
+    pub attributes: Vec<(String, String)>,
     pub geometry: MultiPolygon<f64>,
     // Map<DatasetName, Map<Category/NotStated, Count>>
     pub population_data: std::collections::HashMap<String, std::collections::HashMap<String, u32>>,
+    // Datasets compared with earlier censuses, keyed by dataset then previous year
+    // (mapped onto these boundaries)
+    pub previous_data: std::collections::HashMap<String, std::collections::HashMap<String, std::collections::HashMap<String, u32>>>,
+    // Geometry after each set of masks, keyed by `AppConfig::mask_key`; `geometry` stays unmasked
+    pub masked: std::collections::HashMap<String, MaskedGeometry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaskedGeometry {
+    pub geometry: MultiPolygon<f64>,
+    // Weighted parts of the area dots are drawn into; empty means uniform over `geometry`
+    pub placement: Vec<PlacementZone>,
+    // What went wrong while masking, if a fallback was needed (listed in the generation report)
+    pub issue: Option<String>,
+    // Set when masking would have kept too little of the area and the unmasked boundary was
+    // used instead: the fraction masking would have kept
+    pub unmasked_fallback: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PlacementZone {
+    pub geometry: MultiPolygon<f64>,
+    pub weight: f64,
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,373 @@
+use crate::color;
+use crate::config::{AppConfig, ATTRIBUTE_PREFIX, BoundaryMappingConfig, Vintage};
+use crate::crs::Crs;
+use crate::data;
+use anyhow::{Context, Result, anyhow};
+use csv::ReaderBuilder;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+// Exit codes for `validate`, so scripts can tell a bad config from bad inputs
+pub const EXIT_OK: u8 = 0;
+pub const EXIT_INVALID: u8 = 1;
+pub const EXIT_UNREADABLE_CONFIG: u8 = 2;
+
+// Web map clients rarely go past z22, and tile indices must fit in u32
+const MAX_SUPPORTED_ZOOM: u8 = 22;
+
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// Collects pass/warn/fail lines while checking a config and its inputs.
+#[derive(Default)]
+pub struct ValidationReport {
+    checks: Vec<(Status, String)>,
+}
+
+impl ValidationReport {
+    fn pass(&mut self, msg: impl Into<String>) {
+        self.checks.push((Status::Pass, msg.into()));
+    }
+
+    fn warn(&mut self, msg: impl Into<String>) {
+        self.checks.push((Status::Warn, msg.into()));
+    }
+
+    fn fail(&mut self, msg: impl Into<String>) {
+        self.checks.push((Status::Fail, msg.into()));
+    }
+
+    fn check(&mut self, ok: bool, pass_msg: impl Into<String>, fail_msg: impl Into<String>) -> bool {
+        if ok {
+            self.pass(pass_msg);
+        } else {
+            self.fail(fail_msg);
+        }
+        ok
+    }
+
+    pub fn failures(&self) -> usize {
+        self.checks.iter().filter(|(s, _)| matches!(s, Status::Fail)).count()
+    }
+
+    pub fn print(&self) {
+        for (status, msg) in &self.checks {
+            let label = match status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            println!("  [{}] {}", label, msg);
+        }
+
+        let warnings = self.checks.iter().filter(|(s, _)| matches!(s, Status::Warn)).count();
+        if self.failures() == 0 {
+            println!("Validation passed ({} warning(s))", warnings);
+        } else {
+            println!("Validation FAILED: {} error(s), {} warning(s)", self.failures(), warnings);
+        }
+    }
+}
+
+/// Runs every check against the config at `path`, prints the report and returns the exit code.
+pub fn run(path: &Path) -> u8 {
+    println!("Validating {:?}", path);
+
+    // Parse without the load-time checks so every problem ends up in the report
+    let config = match AppConfig::parse_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("  [FAIL] Config could not be loaded: {:#}", e);
+            println!("Validation FAILED");
+            return EXIT_UNREADABLE_CONFIG;
+        }
+    };
+
+    let mut report = ValidationReport::default();
+    report.pass("Config parsed");
+    validate_config(&config, &mut report);
+    report.print();
+
+    if report.failures() == 0 { EXIT_OK } else { EXIT_INVALID }
+}
+
+pub fn validate_config(config: &AppConfig, report: &mut ValidationReport) {
+    check_inputs(config, report);
+    check_datasets(config, report);
+    check_output(config, report);
+}
+
+fn check_inputs(config: &AppConfig, report: &mut ValidationReport) {
+    for source in data::csv_sources(config).into_iter().chain(data::comparison_sources(config)) {
+        if !report.check(
+            source.path.exists(),
+            format!("CSV exists: {:?}", source.path),
+            format!("CSV not found: {:?} (used by {})", source.path, source.datasets.join(", ")),
+        ) {
+            continue;
+        }
+        match csv_headers(&source.path) {
+            Ok(headers) => {
+                report.check(
+                    headers.iter().any(|h| h == source.join_column),
+                    format!("CSV join column '{}' present in {:?}", source.join_column, source.path),
+                    format!("CSV join column '{}' not in header of {:?}", source.join_column, source.path),
+                );
+                let missing = data::missing_columns(config, &source, &headers);
+                if missing.is_empty() {
+                    report.pass(format!("All columns for {} present in {:?}", source.datasets.join(", "), source.path));
+                }
+                for col in missing {
+                    report.fail(format!("CSV column missing from {:?} for {}", source.path, col));
+                }
+            }
+            Err(e) => report.fail(format!("CSV header unreadable: {:#}", e)),
+        }
+    }
+
+    if let Err(e) = config.check_levels() {
+        report.fail(format!("{:#}", e));
+    }
+    if let Err(e) = config.check_comparisons() {
+        report.fail(format!("{:#}", e));
+    }
+
+    for dataset in config.processing.datasets.values() {
+        for mapping in dataset.compare.iter().filter_map(|c| c.mapping.as_ref()) {
+            check_mapping(mapping, report);
+        }
+    }
+
+    let sources = data::csv_sources(config);
+    for level in config.geography_levels() {
+        let label = format!("Boundary ({})", level.name);
+        if !sources.iter().any(|s| s.level == level.name) {
+            report.warn(format!("{}: no dataset uses this level, it will not be loaded", label));
+            continue;
+        }
+        if !report.check(
+            level.shapefile.exists(),
+            format!("{} file exists: {:?}", label, level.shapefile),
+            format!("{} file not found: {:?}", label, level.shapefile),
+        ) {
+            continue;
+        }
+        match boundary_fields(&level.shapefile) {
+            Ok(fields) => {
+                report.check(
+                    fields.contains(&level.join_column_shape),
+                    format!("{} join column '{}' present", label, level.join_column_shape),
+                    format!(
+                        "{} join column '{}' not found (available: {})",
+                        label,
+                        level.join_column_shape,
+                        sorted(&fields).join(", ")
+                    ),
+                );
+                if let Some(column) = &level.name_column {
+                    if fields.contains(column) {
+                        report.pass(format!("{} name column '{}' present", label, column));
+                    } else {
+                        report.warn(format!("{} name column '{}' not found; areas will have no names", label, column));
+                    }
+                }
+                for column in &level.attributes {
+                    if fields.contains(column) {
+                        report.pass(format!("{} attribute '{}' present", label, column));
+                    } else {
+                        report.warn(format!("{} attribute '{}' not found; it will be missing from the API", label, column));
+                    }
+                }
+            }
+            Err(e) => report.fail(format!("{} attributes unreadable: {:#}", label, e)),
+        }
+        check_crs(level.crs.as_deref(), &level.shapefile, &label, report);
+    }
+
+    if let Err(e) = config.check_masks() {
+        report.fail(format!("{:#}", e));
+    }
+    for mask in config.masks() {
+        let label = format!("Mask '{}'", mask.name);
+        if !report.check(
+            mask.path.exists(),
+            format!("{} exists: {:?}", label, mask.path),
+            format!("{} not found: {:?}", label, mask.path),
+        ) {
+            continue;
+        }
+        check_crs(mask.crs.as_deref(), &mask.path, &label, report);
+        if let Some(property) = &mask.weight_property {
+            match boundary_fields(&mask.path) {
+                Ok(fields) if fields.contains(property) => {
+                    report.pass(format!("{} weight property '{}' present", label, property))
+                }
+                Ok(_) => report.warn(format!(
+                    "{} weight property '{}' not on the first feature; default_weight will be used where it is missing",
+                    label, property
+                )),
+                Err(e) => report.fail(format!("{} attributes unreadable: {:#}", label, e)),
+            }
+        }
+    }
+}
+
+fn check_mapping(mapping: &BoundaryMappingConfig, report: &mut ValidationReport) {
+    if !report.check(
+        mapping.csv.exists(),
+        format!("Boundary mapping exists: {:?}", mapping.csv),
+        format!("Boundary mapping not found: {:?}", mapping.csv),
+    ) {
+        return;
+    }
+    match csv_headers(&mapping.csv) {
+        Ok(headers) => {
+            let columns = [Some(&mapping.from_column), Some(&mapping.to_column), mapping.weight_column.as_ref()];
+            for column in columns.into_iter().flatten() {
+                report.check(
+                    headers.iter().any(|h| h == column),
+                    format!("Boundary mapping column '{}' present", column),
+                    format!("Boundary mapping column '{}' not in header of {:?}", column, mapping.csv),
+                );
+            }
+        }
+        Err(e) => report.fail(format!("Boundary mapping header unreadable: {:#}", e)),
+    }
+}
+
+fn check_crs(configured: Option<&str>, path: &Path, label: &str, report: &mut ValidationReport) {
+    match configured {
+        Some(name) => match Crs::from_name(name) {
+            Ok(crs) => report.pass(format!("{} CRS: {:?}", label, crs)),
+            Err(e) => report.fail(format!("{} CRS: {:#}", label, e)),
+        },
+        None => match Crs::from_prj_file(path) {
+            Ok(Some(crs)) => report.pass(format!("{} CRS from .prj: {:?}", label, crs)),
+            Ok(None) => report.warn(format!(
+                "{} CRS not configured and no .prj found; assuming WGS84 unless the GeoJSON declares one", label
+            )),
+            Err(e) => report.fail(format!("{} CRS: {:#}", label, e)),
+        },
+    }
+}
+
+fn check_datasets(config: &AppConfig, report: &mut ValidationReport) {
+    let datasets = &config.processing.datasets;
+    if datasets.is_empty() {
+        report.fail("No datasets defined under [processing.datasets]");
+        return;
+    }
+
+    let mut dataset_names: Vec<&String> = datasets.keys().collect();
+    dataset_names.sort();
+
+    for dataset_name in dataset_names {
+        let dataset = &datasets[dataset_name];
+        if dataset.categories.is_empty() {
+            report.fail(format!("{}: no categories defined", dataset_name));
+            continue;
+        }
+
+        let mut seen = HashSet::new();
+        let mut ok = true;
+        if dataset_name == ATTRIBUTE_PREFIX {
+            report.fail(format!("{}: dataset name reserved for exported attributes", dataset_name));
+            ok = false;
+        }
+        for category in &dataset.categories {
+            if !seen.insert(category.name.as_str()) {
+                report.fail(format!("{}: duplicate category name '{}'", dataset_name, category.name));
+                ok = false;
+            }
+            if category.name == "Not Stated" {
+                report.fail(format!("{}: 'Not Stated' is reserved for the not_stated column", dataset_name));
+                ok = false;
+            }
+            if category.columns.is_empty() == category.expression.is_none() {
+                report.fail(format!("{} / {}: set exactly one of `columns` or `expression`", dataset_name, category.name));
+                ok = false;
+            }
+            for vintage in [Vintage::Current, Vintage::Previous] {
+                if let Err(e) = category.parsed_expression(vintage) {
+                    report.fail(format!("{} / {}: {:#}", dataset_name, category.name, e));
+                    ok = false;
+                }
+            }
+            if let Err(e) = color::parse_color(&category.loss_color()) {
+                report.fail(format!("{} / {}: loss_color: {}", dataset_name, category.name, e));
+                ok = false;
+            }
+            if let Err(e) = color::parse_color(&category.color) {
+                report.fail(format!("{} / {}: {}", dataset_name, category.name, e));
+                ok = false;
+            }
+        }
+        if ok {
+            report.pass(format!("{}: {} categories", dataset_name, dataset.categories.len()));
+        }
+    }
+}
+
+fn check_output(config: &AppConfig, report: &mut ValidationReport) {
+    let output = &config.output;
+    report.check(
+        output.min_zoom <= output.max_zoom,
+        format!("Zoom range {}..={}", output.min_zoom, output.max_zoom),
+        format!("min_zoom ({}) is greater than max_zoom ({})", output.min_zoom, output.max_zoom),
+    );
+    if output.max_zoom > MAX_SUPPORTED_ZOOM {
+        report.fail(format!("max_zoom {} exceeds the supported maximum of {}", output.max_zoom, MAX_SUPPORTED_ZOOM));
+    }
+    let server = &config.server;
+    if server.dynamic_tiles && server.dynamic_max_zoom > MAX_SUPPORTED_ZOOM {
+        report.fail(format!("server.dynamic_max_zoom {} exceeds the supported maximum of {}", server.dynamic_max_zoom, MAX_SUPPORTED_ZOOM));
+    }
+    if let Some(attribute) = &server.county_attribute {
+        if !config.geography_levels().iter().any(|level| level.attributes.contains(attribute)) {
+            report.warn(format!("server.county_attribute '{}' is not in any level's attributes; county averages will be missing", attribute));
+        }
+    }
+}
+
+fn csv_headers(path: &Path) -> Result<csv::StringRecord> {
+    let file = File::open(path).with_context(|| format!("Failed to open CSV file: {:?}", path))?;
+    let mut rdr = ReaderBuilder::new().from_reader(file);
+    Ok(rdr.headers()?.clone())
+}
+
+/// Attribute names available on the boundary features (dbase fields or GeoJSON properties of the first feature).
+fn boundary_fields(path: &Path) -> Result<HashSet<String>> {
+    let extension = path.extension()
+        .and_then(|e| e.to_str())
+        .map(|s| s.to_lowercase())
+        .ok_or_else(|| anyhow!("Input geometry file has no extension"))?;
+
+    match extension.as_str() {
+        "shp" => {
+            let dbf = path.with_extension("dbf");
+            let reader = shapefile::dbase::Reader::from_path(&dbf)
+                .with_context(|| format!("Failed to open {:?}", dbf))?;
+            Ok(reader.fields().iter().map(|f| f.name().to_string()).collect())
+        }
+        "json" | "geojson" => {
+            let file = File::open(path).with_context(|| format!("Failed to open GeoJSON file: {:?}", path))?;
+            let mut features = geojson::FeatureReader::from_reader(BufReader::new(file)).features();
+            let first = features.next()
+                .ok_or_else(|| anyhow!("GeoJSON has no features"))?
+                .context("Failed to parse first GeoJSON feature")?;
+            Ok(first.properties.map(|p| p.keys().cloned().collect()).unwrap_or_default())
+        }
+        _ => Err(anyhow!("Unsupported geometry format: {}", extension)),
+    }
+}
+
+fn sorted(set: &HashSet<String>) -> Vec<String> {
+    let mut values: Vec<String> = set.iter().cloned().collect();
+    values.sort();
+    values
+}